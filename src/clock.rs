@@ -0,0 +1,24 @@
+use chrono::Utc;
+
+/// Source of the current time, so the schedule can be evaluated as of any instant.
+pub trait Clock {
+    fn now(&self) -> i64;
+}
+
+/// The real current time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp()
+    }
+}
+
+/// A clock stopped at a fixed timestamp.
+pub struct FixedClock(pub i64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> i64 {
+        self.0
+    }
+}
@@ -10,7 +10,13 @@ use tokio::fs::{OpenOptions,File};
 
 use serde::{Serialize, Deserialize};
 
-use crate::repeat::RepeatingEvent;
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+
+use crate::clock::{Clock, FixedClock, SystemClock};
+use crate::repeat::{ClosedWindow, RepeatingEvent};
+use crate::scheduler::Scheduler;
+use crate::timeparse::format_local;
 
 #[derive(Serialize, Deserialize, Debug,poise::ChoiceParameter, Clone, Copy)]
 pub enum ClosedStatus {
@@ -19,30 +25,113 @@ pub enum ClosedStatus {
     Closed,
 }
 
+#[derive(Serialize, Deserialize, Debug, poise::ChoiceParameter, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Close,
+    Open,
+    Window,
+}
+
+/// An event that has no occurrences left, kept for reference instead of cluttering the active schedule.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchivedEvent {
+    pub kind: EventKind,
+    pub archived_at: i64,
+    pub event: RepeatingEvent,
+    /// Length of each window in seconds, for archived closed windows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<i64>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ClosedData {
     pub is_closed: ClosedStatus,
     pub close_events: HashMap<usize,RepeatingEvent>,
     pub open_events: HashMap<usize,RepeatingEvent>,
+    #[serde(default)]
+    pub closed_windows: HashMap<usize,ClosedWindow>,
+    #[serde(default)]
+    pub archived_events: Vec<ArchivedEvent>,
+    /// Dates on which no scheduled events fire, in each event's own timezone.
+    #[serde(default)]
+    pub holidays: Vec<NaiveDate>,
+    /// When a manual Open or Closed status reverts to Automatic, or `None` if it lasts until changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub override_until: Option<i64>,
+}
+
+/// What is determining the current closed status.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ModeCause {
+    Manual {
+        status: ClosedStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<i64>,
+    },
+    CloseEvent { id: usize },
+    OpenEvent { id: usize },
+    ClosedWindow { id: usize },
+    NoSchedule,
+}
+
+impl ModeCause {
+    pub fn describe(&self) -> String {
+        match self {
+            ModeCause::Manual{status, until} => {
+                let status_s = if matches!(status, ClosedStatus::Closed) {"Manually closed"} else {"Manually open"};
+                match until {
+                    Some(until) => format!("{status_s} until <t:{until}:f> (<t:{until}:R>)"),
+                    None => status_s.to_string(),
+                }
+            },
+            ModeCause::CloseEvent{id} => format!("Automatically closed by close event {id}"),
+            ModeCause::OpenEvent{id} => format!("Automatically open by open event {id}"),
+            ModeCause::ClosedWindow{id} => format!("Automatically closed by closed window {id}"),
+            ModeCause::NoSchedule => "Automatically open, no scheduled events have happened".to_string(),
+        }
+    }
 }
 
 impl Default for ClosedData {
-    fn default() -> Self {ClosedData{is_closed: ClosedStatus::Automatic, close_events: HashMap::new(), open_events: HashMap::new()}}
+    fn default() -> Self {ClosedData{is_closed: ClosedStatus::Automatic, close_events: HashMap::new(), open_events: HashMap::new(), closed_windows: HashMap::new(), archived_events: Vec::new(), holidays: Vec::new(), override_until: None}}
 }
 
 impl ClosedData {
-    pub fn is_currently_closed(&self) -> bool {
-        match self.is_closed {
+    /// The manual status in effect, which is Automatic once a timed override has expired.
+    pub fn manual_status(&self, clock: &impl Clock) -> ClosedStatus {
+        match self.override_until {
+            Some(until) if until <= clock.now() => ClosedStatus::Automatic,
+            _ => self.is_closed,
+        }
+    }
+
+    /// Sets a timed override back to Automatic once it has expired, returning whether it did.
+    pub fn clear_expired_override(&mut self, clock: &impl Clock) -> bool {
+        if self.override_until.is_some_and(|x| x <= clock.now()) {
+            self.is_closed = ClosedStatus::Automatic;
+            self.override_until = None;
+            return true;
+        }
+        return false;
+    }
+
+    pub fn is_currently_closed(&self, clock: &impl Clock) -> bool {
+        let now = clock.now();
+        match self.manual_status(clock) {
             ClosedStatus::Open => return false,
             ClosedStatus::Closed => return true,
             ClosedStatus::Automatic => (),
         }
 
-        if self.close_events.is_empty() || self.open_events.is_empty() {return false;}
+        if self.closed_windows.values().any(|x| x.is_active(now, &self.holidays)) {
+            return true;
+        }
 
+        //Whichever kind of event happened most recently wins, and a lone close event stays in effect once it has happened.
         let mut close_elapsed = i64::MAX;
         for event in self.close_events.values() {
-            let elapsed = event.elapsed();
+            let elapsed = event.elapsed(now, &self.holidays);
             if close_elapsed > elapsed {
                 close_elapsed = elapsed;
             }
@@ -50,7 +139,7 @@ impl ClosedData {
 
         let mut open_elapsed = i64::MAX;
         for event in self.open_events.values() {
-            let elapsed = event.elapsed();
+            let elapsed = event.elapsed(now, &self.holidays);
             if open_elapsed > elapsed {
                 open_elapsed = elapsed;
             }
@@ -59,10 +148,125 @@ impl ClosedData {
         return close_elapsed < open_elapsed;
     }
 
-    pub fn next_close_event(&self) -> i64 {
+    /// Looks up a close event, open event or the start of a closed window by id.
+    pub fn event_mut(&mut self, kind: EventKind, id: usize) -> Option<&mut RepeatingEvent> {
+        match kind {
+            EventKind::Close => self.close_events.get_mut(&id),
+            EventKind::Open => self.open_events.get_mut(&id),
+            EventKind::Window => self.closed_windows.get_mut(&id).map(|x| &mut x.start),
+        }
+    }
+
+    /// The id for a new event of a kind, after every live and archived one so an archived event's id is never reused.
+    pub fn next_id(&self, kind: EventKind) -> usize {
+        let live = match kind {
+            EventKind::Close => self.close_events.keys().max(),
+            EventKind::Open => self.open_events.keys().max(),
+            EventKind::Window => self.closed_windows.keys().max(),
+        };
+        let archived = self.archived_events.iter().filter(|x| x.kind == kind).map(|x| &x.event.id).max();
+        return live.max(archived).map_or(0, |x| x + 1);
+    }
+
+    /// Moves finished events into `archived_events`, returning how many were moved.
+    ///
+    /// Finished open and close events are only archived once a more recent event has happened, since until then they still decide the status.
+    pub fn archive_expired(&mut self, clock: &impl Clock) -> usize {
+        let now = clock.now();
+        let mut archived: Vec<ArchivedEvent> = Vec::new();
+        let holidays = self.holidays.clone();
+
+        let most_recent: Vec<(EventKind, usize, i64)> = self.close_events.values().map(|x| (EventKind::Close, x.id, x.most_recent(now, &holidays)))
+            .chain(self.open_events.values().map(|x| (EventKind::Open, x.id, x.most_recent(now, &holidays))))
+            .collect();
+        let superseded = |kind: EventKind, event: &RepeatingEvent| -> bool {
+            let own = event.most_recent(now, &holidays);
+            most_recent.iter().any(|(other_kind, other_id, other)| (*other_kind, *other_id) != (kind, event.id) && *other > own)
+        };
+
+        for (kind, events) in [(EventKind::Close, &mut self.close_events), (EventKind::Open, &mut self.open_events)] {
+            let expired: Vec<usize> = events.values().filter(|x| x.is_finished(now) && superseded(kind, x)).map(|x| x.id).collect();
+            for id in expired {
+                let event = events.remove(&id).unwrap();
+                archived.push(ArchivedEvent{kind, archived_at: now, event, duration: None});
+            }
+        }
+
+        let expired: Vec<usize> = self.closed_windows.values().filter(|x| x.is_finished(now)).map(|x| x.id).collect();
+        for id in expired {
+            let window = self.closed_windows.remove(&id).unwrap();
+            archived.push(ArchivedEvent{kind: EventKind::Window, archived_at: now, event: window.start, duration: Some(window.duration)});
+        }
+
+        let count = archived.len();
+        self.archived_events.extend(archived);
+        return count;
+    }
+
+    /// Explains which manual setting or scheduled event is responsible for `is_currently_closed`.
+    pub fn mode_cause(&self, clock: &impl Clock) -> ModeCause {
+        let now = clock.now();
+        match self.manual_status(clock) {
+            ClosedStatus::Automatic => (),
+            status => return ModeCause::Manual{status, until: self.override_until},
+        }
+
+        if let Some(window) = self.closed_windows.values().find(|x| x.is_active(now, &self.holidays)) {
+            return ModeCause::ClosedWindow{id: window.id};
+        }
+
+        let latest_close = self.close_events.values().min_by_key(|x| x.elapsed(now, &self.holidays));
+        let latest_open = self.open_events.values().min_by_key(|x| x.elapsed(now, &self.holidays));
+
+        match (latest_close, latest_open) {
+            (Some(close), Some(open)) if close.elapsed(now, &self.holidays) < open.elapsed(now, &self.holidays) => ModeCause::CloseEvent{id: close.id},
+            (_, Some(open)) if open.elapsed(now, &self.holidays) < i64::MAX => ModeCause::OpenEvent{id: open.id},
+            (Some(close), None) if close.elapsed(now, &self.holidays) < i64::MAX => ModeCause::CloseEvent{id: close.id},
+            _ => ModeCause::NoSchedule,
+        }
+    }
+
+    /// Label of the event or window named by `cause`, if it has one.
+    pub fn cause_label(&self, cause: &ModeCause) -> Option<&str> {
+        let event = match cause {
+            ModeCause::CloseEvent{id} => self.close_events.get(id),
+            ModeCause::OpenEvent{id} => self.open_events.get(id),
+            ModeCause::ClosedWindow{id} => self.closed_windows.get(id).map(|x| &x.start),
+            ModeCause::Manual{..} | ModeCause::NoSchedule => None,
+        };
+        return event.and_then(|x| x.label.as_deref());
+    }
+
+    /// Labels of the events and windows that close the headless (or open it, if `closing` is false) exactly at `at`.
+    pub fn labels_at(&self, at: i64, closing: bool) -> Vec<&str> {
+        let before = at.saturating_sub(1);
+        let events = if closing {&self.close_events} else {&self.open_events};
+
+        let mut labels: Vec<&str> = events.values()
+            .filter(|x| x.next(before, &self.holidays) == at)
+            .filter_map(|x| x.label.as_deref())
+            .collect();
+        for window in self.closed_windows.values() {
+            let fires = if closing {window.next_start(before, &self.holidays)} else {window.next_end(before, &self.holidays)};
+            if fires == at {
+                labels.extend(window.start.label.as_deref());
+            }
+        }
+        return labels;
+    }
+
+    pub fn next_close_event(&self, clock: &impl Clock) -> i64 {
+        let now = clock.now();
         let mut soonest_event: i64 = i64::MAX;
         for event in self.close_events.values() {
-            let next_instance = event.next();
+            let next_instance = event.next(now, &self.holidays);
+            if next_instance < soonest_event {
+                soonest_event = next_instance;
+            }
+        }
+
+        for window in self.closed_windows.values() {
+            let next_instance = window.next_start(now, &self.holidays);
             if next_instance < soonest_event {
                 soonest_event = next_instance;
             }
@@ -71,10 +275,18 @@ impl ClosedData {
         return soonest_event;
     }
 
-    pub fn next_open_event(&self) -> i64 {
+    pub fn next_open_event(&self, clock: &impl Clock) -> i64 {
+        let now = clock.now();
         let mut soonest_event: i64 = i64::MAX;
         for event in self.open_events.values() {
-            let next_instance = event.next();
+            let next_instance = event.next(now, &self.holidays);
+            if next_instance < soonest_event {
+                soonest_event = next_instance;
+            }
+        }
+
+        for window in self.closed_windows.values() {
+            let next_instance = window.next_end(now, &self.holidays);
             if next_instance < soonest_event {
                 soonest_event = next_instance;
             }
@@ -82,10 +294,333 @@ impl ClosedData {
 
         return soonest_event;
     }
+
+    /// The next time anything that could change the status happens: an event firing, a window ending or a timed override expiring.
+    pub fn next_change(&self, clock: &impl Clock) -> i64 {
+        let expiry = self.override_until.filter(|x| *x > clock.now()).unwrap_or(i64::MAX);
+        return self.next_close_event(clock).min(self.next_open_event(clock)).min(expiry);
+    }
+
+    /// The next `count` times the closed status changes after the clock's current time, along with what causes each change.
+    ///
+    /// Scheduled events that don't change the status, like a close event while already closed, are passed over.
+    pub fn transitions(&self, clock: &impl Clock, count: usize) -> Vec<Transition> {
+        let mut transitions: Vec<Transition> = Vec::new();
+        if !matches!(self.manual_status(clock), ClosedStatus::Automatic) && self.override_until.is_none() {
+            return transitions;
+        }
+
+        let mut now = clock.now();
+        let mut closed = self.is_currently_closed(&FixedClock(now));
+        //Bounds the search when events keep firing without changing anything.
+        for _ in 0..count.saturating_mul(64).max(MAX_TRANSITION_STEPS) {
+            if transitions.len() >= count {
+                break;
+            }
+            let next = self.next_change(&FixedClock(now));
+            if next == i64::MAX {
+                break;
+            }
+            now = next;
+            let at = FixedClock(now);
+            if self.is_currently_closed(&at) != closed {
+                closed = !closed;
+                let cause = self.mode_cause(&at);
+                transitions.push(Transition{at: now, closed, cause, label: self.cause_label(&cause).map(|x| x.to_string())});
+            }
+        }
+        return transitions;
+    }
+}
+
+const MAX_TRANSITION_STEPS: usize = 1000;
+
+/// A point in time where the closed status changes.
+#[derive(Serialize, Debug, Clone)]
+pub struct Transition {
+    pub at: i64,
+    pub closed: bool,
+    pub cause: ModeCause,
+    /// Label of the event or window causing the change, if it has one.
+    pub label: Option<String>,
+}
+
+impl Transition {
+    pub fn localized(self, tz: &Tz) -> Localized<Transition> {
+        Localized{at_local: format_local(self.at, tz), value: self}
+    }
+}
+
+/// A value along with its time in the server's timezone.
+#[derive(Serialize, Debug, Clone)]
+pub struct Localized<T> {
+    #[serde(flatten)]
+    pub value: T,
+    pub at_local: String,
+}
+
+
+/// The whitelist files tracked by the revision log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WhitelistName {
+    Admin,
+    Auth,
+    Closed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op")]
+pub enum Change {
+    Added { list: WhitelistName, uid: String },
+    Removed { list: WhitelistName, uid: String },
+    Mode {
+        status: ClosedStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<i64>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RevisionEntry {
+    pub revision: u64,
+    pub change: Change,
+}
+
+//Enough history for clients polling every few minutes, while keeping revisions.json small.
+const MAX_REVISION_ENTRIES: usize = 5000;
+
+/// Monotonic revision of the whitelists, along with the recent changes that produced it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RevisionLog {
+    pub revision: u64,
+    pub changes: Vec<RevisionEntry>,
+    /// Newest revision that has had changes dropped from the log.
+    #[serde(default)]
+    pub trimmed_through: u64,
+}
+
+impl RevisionLog {
+    /// Whether the retained changes are enough to bring a client at `since` up to date.
+    pub fn covers(&self, since: u64) -> bool {
+        since <= self.revision && since >= self.trimmed_through
+    }
+
+    pub fn changes_since(&self, since: u64) -> impl Iterator<Item = &Change> {
+        self.changes.iter().filter(move |x| x.revision > since).map(|x| &x.change)
+    }
+}
+
+/// Why the effective status changed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryReason {
+    /// A scheduled event or window fired.
+    Schedule,
+    /// An admin used `setclosed`.
+    Manual,
+    /// A timed manual override ran out and the schedule took over again.
+    OverrideExpired,
+    /// Noticed when the bot started, so the change may have happened earlier while it was offline.
+    Startup,
+}
+
+impl HistoryReason {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            HistoryReason::Schedule => "scheduled",
+            HistoryReason::Manual => "set manually",
+            HistoryReason::OverrideExpired => "manual override expired",
+            HistoryReason::Startup => "noticed at startup, may have changed earlier",
+        }
+    }
+}
+
+/// A change to the effective status, either between open and closed or between manual and automatic.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub at: i64,
+    pub closed: bool,
+    pub cause: ModeCause,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub reason: HistoryReason,
+}
+
+impl HistoryEntry {
+    pub fn is_manual(&self) -> bool {
+        matches!(self.cause, ModeCause::Manual{..})
+    }
+}
+
+//Years of history for a schedule that changes a few times a day.
+const MAX_HISTORY_ENTRIES: usize = 10000;
+
+/// Every effective status change recorded by the scheduler, oldest first.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HistoryLog {
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl HistoryLog {
+    /// The change that was in effect at `at`, or None if it's before the history starts.
+    pub fn status_at(&self, at: i64) -> Option<&HistoryEntry> {
+        let index = self.entries.partition_point(|x| x.at <= at);
+        return index.checked_sub(1).map(|x| &self.entries[x]);
+    }
+
+    /// Changes made from `from` up to and including `to`.
+    pub fn between(&self, from: i64, to: i64) -> &[HistoryEntry] {
+        let start = self.entries.partition_point(|x| x.at < from);
+        let end = self.entries.partition_point(|x| x.at <= to);
+        return &self.entries[start..end.max(start)];
+    }
+
+    pub fn last(&self) -> Option<&HistoryEntry> {
+        self.entries.last()
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        self.entries.push(entry);
+        if self.entries.len() > MAX_HISTORY_ENTRIES {
+            let excess = self.entries.len() - MAX_HISTORY_ENTRIES;
+            self.entries.drain(0..excess);
+        }
+    }
+}
+
+/// The revision log, locked for writing while a whitelist file or the closed status is changed.
+///
+/// Lock it before the file being changed and record once that's written, so anyone reading the lists
+/// while holding the log sees every change together with its revision.
+pub struct RevisionLock {
+    file_path: PathBuf,
+    file: File,
+    log: RevisionLog,
+}
+
+/// Locks the revision log. Take it after the expiries and before any whitelist file, as purging expired entries does.
+pub async fn lock_revisions(ctx: Option<&Context<'_>>) -> Result<RevisionLock, Error> {
+    let (file_path, file, log) = load_json::<RevisionLog>(ctx, "revisions.json".to_string(), false).await?;
+    Ok(RevisionLock{file_path, file, log})
+}
+
+impl RevisionLock {
+    /// Bumps the revision for a single mutation made up of `changes` and releases the log, returning the new revision.
+    pub async fn record(self, ctx: Option<&Context<'_>>, changes: Vec<Change>) -> Result<u64, Error> {
+        let RevisionLock{file_path, file, mut log} = self;
+        if changes.is_empty() {
+            file.unlock()?;
+            return Ok(log.revision);
+        }
+
+        log.revision += 1;
+        let revision = log.revision;
+        log.changes.extend(changes.into_iter().map(|change| RevisionEntry{revision, change}));
+
+        if log.changes.len() > MAX_REVISION_ENTRIES {
+            let excess = log.changes.len() - MAX_REVISION_ENTRIES;
+            log.trimmed_through = log.changes[excess - 1].revision;
+            log.changes.drain(0..excess);
+        }
+
+        write_tmp_and_copy(ctx, &file_path, file, &serde_json::to_string(&log)?).await?;
+        Ok(revision)
+    }
+
+    /// Releases the log without bumping the revision, for when nothing was changed after all.
+    pub fn release(self) -> Result<(), Error> {
+        self.file.unlock()?;
+        Ok(())
+    }
+}
+
+/// When a temporary entry in the admin or closed whitelist stops applying.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Expiry {
+    pub list: WhitelistName,
+    pub uid: String,
+    pub expires_at: i64,
+}
+
+/// Expiry times for temporary whitelist entries, kept apart so the whitelist files stay plain lists of UserIDs.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct WhitelistExpiries {
+    pub entries: Vec<Expiry>,
+}
+
+impl WhitelistExpiries {
+    pub fn expires_at(&self, list: WhitelistName, uid: &str) -> Option<i64> {
+        self.entries.iter().find(|x| x.list == list && x.uid == uid).map(|x| x.expires_at)
+    }
+
+    /// Whether an entry in `list` still applies at `now`, entries without an expiry always do.
+    pub fn is_active(&self, list: WhitelistName, uid: &str, now: i64) -> bool {
+        self.expires_at(list, uid).is_none_or(|x| x > now)
+    }
+
+    /// Sets when an entry expires, or makes it permanent with None.
+    pub fn set(&mut self, list: WhitelistName, uid: &str, expires_at: Option<i64>) {
+        self.entries.retain(|x| !(x.list == list && x.uid == uid));
+        if let Some(expires_at) = expires_at {
+            self.entries.push(Expiry{list, uid: uid.to_string(), expires_at});
+        }
+    }
 }
 
+pub async fn load_expiries() -> Result<WhitelistExpiries, Error> {
+    let (_file_path, file, expiries) = load_json::<WhitelistExpiries>(None, "expiries.json".to_string(), true).await?;
+    file.unlock()?;
+    Ok(expiries)
+}
 
-pub struct Data {} // User data, which is stored and accessible in all command invocations
+/// Removes expired temporary entries from the whitelist files, returning how many were removed.
+pub async fn purge_expired_entries() -> Result<usize, Error> {
+    let (file_path, file, mut expiries) = load_json::<WhitelistExpiries>(None, "expiries.json".to_string(), false).await?;
+
+    let now = SystemClock.now();
+    let (expired, remaining): (Vec<Expiry>, Vec<Expiry>) = expiries.entries.drain(..).partition(|x| x.expires_at <= now);
+    if expired.is_empty() {
+        file.unlock()?;
+        return Ok(0);
+    }
+
+    let revisions = lock_revisions(None).await?;
+    let dir = get_dir()?;
+    let mut changes: Vec<Change> = Vec::new();
+    for (list, file_name) in [(WhitelistName::Admin, "usersadmin.txt"), (WhitelistName::Closed, "usersclosed.txt")] {
+        let uids: Vec<&str> = expired.iter().filter(|x| x.list == list).map(|x| x.uid.as_str()).collect();
+        if uids.is_empty() {
+            continue;
+        }
+
+        let list_path = dir.join(file_name);
+        let mut list_file = try_get_file(None, &list_path).await?;
+        list_file.lock_exclusive()?;
+
+        let mut contents = String::new();
+        BufReader::new(&mut list_file).read_to_string(&mut contents).await?;
+        let (removed, kept): (Vec<&str>, Vec<&str>) = contents.lines().partition(|x| uids.contains(x));
+        if removed.is_empty() {
+            list_file.unlock()?;
+            continue;
+        }
+
+        changes.extend(removed.iter().map(|x| Change::Removed{list, uid: x.to_string()}));
+        write_tmp_and_copy(None, &list_path, list_file, &kept.join("\n")).await?;
+    }
+
+    let count = changes.len();
+    revisions.record(None, changes).await?;
+
+    expiries.entries = remaining;
+    write_tmp_and_copy(None, &file_path, file, &serde_json::to_string(&expiries)?).await?;
+    Ok(count)
+}
+
+// User data, which is stored and accessible in all command invocations
+pub struct Data {
+    pub scheduler: Scheduler,
+}
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Context<'a> = poise::Context<'a, Data, Error>;
 
@@ -122,13 +657,15 @@ pub async fn try_get_file(ctx: Option<&Context<'_>>, file_path: &PathBuf) -> Res
     }
 }
 
-pub async fn write_tmp_and_copy(ctx: &Context<'_>, file_path: &PathBuf, file:File, data: &str) -> Result<(), Error> {
+pub async fn write_tmp_and_copy(ctx: Option<&Context<'_>>, file_path: &PathBuf, file:File, data: &str) -> Result<(), Error> {
     let tmp_file_path = file_path.with_extension("tmp");
 
     let mut tmp_file = match OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&tmp_file_path).await {
         Err(e) => {
             println!("openoptions:{e:?}");
-            ctx.say("Encountered error accessing files").await?;
+            if let Some(x) = ctx {
+                x.say("Encountered error accessing files").await?;
+            }
             return Err(Box::new(e));
         },
         Ok(res) => res
@@ -140,6 +677,12 @@ pub async fn write_tmp_and_copy(ctx: &Context<'_>, file_path: &PathBuf, file:Fil
     tmp_file.unlock()?;
     file.unlock()?;
     tokio::fs::copy(&tmp_file_path, &file_path).await?;
+
+    if let Some(x) = ctx {
+        if file_path.file_name().is_some_and(|x| x == "closed.json") {
+            x.data().scheduler.schedule_changed();
+        }
+    }
     Ok(())
 }
 
@@ -165,4 +708,21 @@ pub async fn load_json<T: Default + for<'a> Deserialize<'a>>(ctx: Option<&Contex
         serde_json::from_str(&data_string)?
     };
     return Ok((file_path,file,data));
+}
+
+/// Archives finished events and clears an expired override in closed.json, leaving the file untouched if there is nothing to do.
+///
+/// Returns how many changes were made, counting a cleared override as one, so callers know to reschedule.
+pub async fn archive_expired_events() -> Result<usize, Error> {
+    let (file_path, file, mut data) = load_json::<ClosedData>(None, "closed.json".to_string(), false).await?;
+
+    let count = data.archive_expired(&SystemClock);
+    let cleared = data.clear_expired_override(&SystemClock);
+    if count == 0 && !cleared {
+        file.unlock()?;
+        return Ok(0);
+    }
+
+    write_tmp_and_copy(None, &file_path, file, &serde_json::to_string(&data)?).await?;
+    Ok(count + cleared as usize)
 }
\ No newline at end of file
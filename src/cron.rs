@@ -0,0 +1,277 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate};
+use chrono_tz::Tz;
+
+use crate::repeat::resolve_local;
+
+//Long enough to find a February 29th from anywhere, so any satisfiable expression is found.
+const SEARCH_DAYS: i64 = 366 * 8;
+
+const MONTH_NAMES: [&str; 12] = ["JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+const DAY_NAMES: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// A standard five field cron expression (`minute hour day-of-month month day-of-week`).
+///
+/// Fields accept `*`, numbers, names for months and weekdays, ranges, lists and `/` steps.
+/// Like most cron implementations, when both day fields are restricted a day matching either of them fires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_value(value: &str, min: u32, max: u32, names: &[&str]) -> Result<u32, String> {
+    let upper = value.to_uppercase();
+    if let Some(index) = names.iter().position(|x| *x == upper) {
+        return Ok(index as u32 + min);
+    }
+    let parsed = value.parse::<u32>().map_err(|_| format!("`{value}` is not a valid value"))?;
+    if parsed < min || parsed > max {
+        return Err(format!("`{value}` is out of range {min}-{max}"));
+    }
+    Ok(parsed)
+}
+
+/// Parses one field into a bitmask where bit `i` is set if value `i` matches.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let mut mask: u64 = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step.parse::<u32>().map_err(|_| format!("`{step}` is not a valid step"))?;
+                if step == 0 {
+                    return Err("Step can't be 0".to_string());
+                }
+                (range, step)
+            },
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, min, max, names)?, parse_value(end, min, max, names)?)
+        } else {
+            let start = parse_value(range, min, max, names)?;
+            //A single value with a step, like `5/15`, runs to the end of the range.
+            (start, if part.contains('/') {max} else {start})
+        };
+
+        if start > end {
+            return Err(format!("Range `{range}` is backwards"));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl CronSchedule {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let expanded = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Expected 5 fields (minute hour day month weekday) but got {}", fields.len()));
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7, &DAY_NAMES)?;
+        //Both 0 and 7 mean Sunday.
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+
+        Ok(CronSchedule {
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])? as u32,
+            days: parse_field(fields[2], 1, 31, &[])? as u32,
+            months: parse_field(fields[3], 1, 12, &MONTH_NAMES)? as u16,
+            weekdays: (weekdays & 0x7f) as u8,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day_matches = self.days & (1 << date.day()) != 0;
+        let weekday_matches = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday_matches,
+            (false, true) => day_matches,
+            (false, false) => day_matches || weekday_matches,
+        }
+    }
+
+    fn times(&self) -> impl DoubleEndedIterator<Item = (u32, u32)> + '_ {
+        (0..24u32).filter(|h| self.hours & (1 << h) != 0)
+            .flat_map(|h| (0..60u32).filter(|m| self.minutes & (1 << m) != 0).map(move |m| (h, m)))
+    }
+
+    /// First time strictly after `after` that the expression fires, in the given timezone.
+    pub fn next_after(&self, tz: &Tz, after: i64) -> Option<i64> {
+        let local = DateTime::from_timestamp(after, 0)?.with_timezone(tz).naive_local();
+        //Start a day early so times pushed forward over a DST gap aren't missed.
+        let start = local.date() - Duration::days(1);
+
+        for day in 0..SEARCH_DAYS {
+            let date = start + Duration::days(day);
+            if !self.matches_date(date) {
+                continue;
+            }
+            for (h, m) in self.times() {
+                let time = resolve_local(tz, date.and_hms_opt(h, m, 0)?);
+                if time > after {
+                    return Some(time);
+                }
+            }
+        }
+        None
+    }
+
+    /// Most recent time at or before `at` that the expression fired, in the given timezone.
+    pub fn last_at_or_before(&self, tz: &Tz, at: i64) -> Option<i64> {
+        let local = DateTime::from_timestamp(at, 0)?.with_timezone(tz).naive_local();
+        let start = local.date() + Duration::days(1);
+
+        for day in 0..SEARCH_DAYS {
+            let date = start - Duration::days(day);
+            if !self.matches_date(date) {
+                continue;
+            }
+            for (h, m) in self.times().rev() {
+                let time = resolve_local(tz, date.and_hms_opt(h, m, 0)?);
+                if time <= at {
+                    return Some(time);
+                }
+            }
+        }
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Wednesday 2024-05-01 12:00 UTC.
+    const NOW: i64 = 1_714_564_800;
+
+    fn cron(expr: &str) -> CronSchedule {
+        CronSchedule::parse(expr).unwrap()
+    }
+
+    fn bits(mask: u64) -> Vec<u32> {
+        (0..64).filter(|x| mask & (1 << x) != 0).collect()
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn ranges_and_lists() {
+        let schedule = cron("0,30 9-11,17 * * *");
+        assert_eq!(bits(schedule.minutes), vec![0, 30]);
+        assert_eq!(bits(schedule.hours as u64), vec![9, 10, 11, 17]);
+        assert!(CronSchedule::parse("0 17-9 * * *").is_err());
+        assert!(CronSchedule::parse("60 * * * *").is_err());
+        assert!(CronSchedule::parse("0 0 0 * *").is_err());
+    }
+
+    #[test]
+    fn steps() {
+        assert_eq!(bits(cron("*/15 * * * *").minutes), vec![0, 15, 30, 45]);
+        assert_eq!(bits(cron("5/20 * * * *").minutes), vec![5, 25, 45]);
+        assert_eq!(bits(cron("10-30/10 * * * *").minutes), vec![10, 20, 30]);
+        assert_eq!(bits(cron("0 0 */10 * *").days as u64), vec![1, 11, 21, 31]);
+        assert!(CronSchedule::parse("*/0 * * * *").is_err());
+        assert!(CronSchedule::parse("*/x * * * *").is_err());
+    }
+
+    #[test]
+    fn names() {
+        let schedule = cron("0 0 * jan-MAR,Dec MON,fri");
+        assert_eq!(bits(schedule.months as u64), vec![1, 2, 3, 12]);
+        assert_eq!(bits(schedule.weekdays as u64), vec![1, 5]);
+        //Both 0 and 7 are Sunday.
+        assert_eq!(cron("0 0 * * 7").weekdays, cron("0 0 * * SUN").weekdays);
+        assert_eq!(cron("@weekly"), cron("0 0 * * 0"));
+        assert!(CronSchedule::parse("0 0 * FOO *").is_err());
+        assert!(CronSchedule::parse("0 0 * *").is_err());
+    }
+
+    #[test]
+    fn day_fields_are_ored_when_both_restricted() {
+        let either = cron("0 0 13 * FRI");
+        assert!(either.matches_date(date(2024, 5, 3)));
+        assert!(either.matches_date(date(2024, 5, 13)));
+        assert!(!either.matches_date(date(2024, 5, 14)));
+
+        let day_only = cron("0 0 13 * *");
+        assert!(!day_only.matches_date(date(2024, 5, 3)));
+        assert!(day_only.matches_date(date(2024, 5, 13)));
+
+        //A stepped wildcard still counts as unrestricted, so only the weekday applies.
+        let weekday_only = cron("0 0 */1 * FRI");
+        assert!(weekday_only.matches_date(date(2024, 5, 3)));
+        assert!(!weekday_only.matches_date(date(2024, 5, 13)));
+
+        assert_eq!(either.next_after(&chrono_tz::UTC, NOW), Some(1_714_694_400));
+        assert_eq!(either.next_after(&chrono_tz::UTC, 1_714_694_400), Some(1_715_299_200));
+        assert_eq!(either.next_after(&chrono_tz::UTC, 1_715_299_200), Some(1_715_558_400));
+    }
+
+    #[test]
+    fn impossible_dates_never_fire() {
+        assert_eq!(cron("0 0 31 2 *").next_after(&chrono_tz::UTC, NOW), None);
+        assert_eq!(cron("0 0 30 2 *").last_at_or_before(&chrono_tz::UTC, NOW), None);
+        assert_eq!(cron("0 0 31 4,6,9,11 *").next_after(&chrono_tz::UTC, NOW), None);
+        //Leap days are rare but possible.
+        assert_eq!(cron("0 12 29 2 *").next_after(&chrono_tz::UTC, 1_735_689_600), Some(1_835_438_400));
+    }
+
+    #[test]
+    fn searches_forwards() {
+        let schedule = cron("0 20 * * FRI");
+        assert_eq!(schedule.next_after(&chrono_tz::UTC, NOW), Some(1_714_766_400));
+        //Strictly after, so a time it fires at moves on to the next week.
+        assert_eq!(schedule.next_after(&chrono_tz::UTC, 1_714_766_400), Some(1_714_766_400 + 604800));
+        assert_eq!(cron("15 * * * *").next_after(&chrono_tz::UTC, NOW), Some(1_714_565_700));
+    }
+
+    #[test]
+    fn searches_backwards() {
+        let schedule = cron("0 20 * * FRI");
+        assert_eq!(schedule.last_at_or_before(&chrono_tz::UTC, NOW), Some(1_714_161_600));
+        assert_eq!(schedule.last_at_or_before(&chrono_tz::UTC, 1_714_766_400), Some(1_714_766_400));
+        assert_eq!(schedule.last_at_or_before(&chrono_tz::UTC, 1_714_766_399), Some(1_714_161_600));
+    }
+
+    #[test]
+    fn follows_local_time_over_dst() {
+        let berlin = chrono_tz::Europe::Berlin;
+        let schedule = cron("30 2 * * *");
+        //02:30 doesn't exist on 2024-03-31, so it fires at 02:30 winter time, which is 03:30 summer time.
+        assert_eq!(schedule.next_after(&berlin, 1_711_800_000), Some(1_711_848_600));
+        assert_eq!(schedule.next_after(&berlin, 1_711_848_600), Some(1_711_931_400));
+        assert_eq!(schedule.last_at_or_before(&berlin, 1_711_931_399), Some(1_711_848_600));
+    }
+}
@@ -3,6 +3,9 @@ pub mod admin;
 pub mod checks;
 pub mod common;
 pub mod closedwhitelist;
+pub mod announce;
+pub mod guildevents;
+pub mod import;
 
 use fs4::tokio::AsyncFileExt;
 use serde::{Serialize, Deserialize};
@@ -13,8 +16,16 @@ use checks::*;
 use mainwhitelist::*;
 use admin::*;
 use closedwhitelist::*;
-use common::GeneralData;
+use announce::*;
+use guildevents::*;
+use import::*;
+use common::{GeneralData, load_timezone};
+use crate::clock::{FixedClock, SystemClock};
 use crate::commonio::*;
+use crate::lint::lint_schedule;
+use crate::scheduler::Scheduler;
+use crate::timeparse::parse_time;
+use crate::whitelist::WhitelistSnapshot;
 
 
 #[derive(Serialize,Deserialize,Debug)]
@@ -60,17 +71,32 @@ pub async fn status(
         num_players = Some(response.list.len());
     }
 
-    let next_close = data.next_close_event();
-    let next_open = data.next_open_event();
-    let close_status = match data.is_closed {
+    let next_close = data.next_close_event(&SystemClock);
+    let next_open = data.next_open_event(&SystemClock);
+    let close_status = match data.manual_status(&SystemClock) {
         ClosedStatus::Open => "Manually open",
         ClosedStatus::Closed => "Manually closed",
-        ClosedStatus::Automatic => if data.is_currently_closed() {"Automatically closed"} else {"Automatically open"},
+        ClosedStatus::Automatic => if data.is_currently_closed(&SystemClock) {"Automatically closed"} else {"Automatically open"},
     };
 
-    let next_open_str =  if next_open == i64::MAX {"No scheduled openings".to_string()} else {format!("<t:{next_open}:f>")};
+    let close_status = match (data.manual_status(&SystemClock), data.override_until) {
+        (ClosedStatus::Automatic, _) | (_, None) => close_status.to_string(),
+        (_, Some(until)) => format!("{close_status}, back to automatic <t:{until}:R> (<t:{until}:f>)"),
+    };
+
+    let close_status = match data.cause_label(&data.mode_cause(&SystemClock)) {
+        Some(label) => format!("{close_status} ({label})"),
+        None => close_status,
+    };
+
+    let labels_s = |at: i64, closing: bool| {
+        let labels = data.labels_at(at, closing);
+        if labels.is_empty() {String::new()} else {format!(" ({})", labels.join(", "))}
+    };
+
+    let next_open_str =  if next_open == i64::MAX {"No scheduled openings".to_string()} else {format!("<t:{next_open}:f>{}", labels_s(next_open, false))};
 
-    let next_closed_str =  if next_close == i64::MAX {"No scheduled closings".to_string()} else {format!("<t:{next_close}:f>")};
+    let next_closed_str =  if next_close == i64::MAX {"No scheduled closings".to_string()} else {format!("<t:{next_close}:f>{}", labels_s(next_close, true))};
 
     ctx.send(|b| b.embed(|embed| {
         embed.color(serenity::colours::branding::BLURPLE);
@@ -87,6 +113,189 @@ pub async fn status(
     Ok(())
 }
 
+/// Commands for inspecting the open and close schedule
+#[poise::command(slash_command, subcommands("preview", "lint"), check = "channel_check")]
+pub async fn schedule(
+    _ctx: Context<'_>,
+) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Show the whitelist status at a point in time and the changes that follow it
+#[poise::command(slash_command, check = "channel_check")]
+pub async fn preview(
+    ctx: Context<'_>,
+    #[description = "Time to evaluate the schedule at, e.g. `friday 20:00` in the server's timezone, defaults to now"]
+    at: Option<String>,
+    #[description = "How many upcoming status changes to show, defaults to 5"]
+    count: Option<usize>,
+) -> Result<(), Error> {
+    let now = chrono::Utc::now().timestamp();
+    let at = match at {
+        Some(at) => match parse_time(&at, &load_timezone().await?, now) {
+            Ok(at) => at,
+            Err(e) => {
+                ctx.say(e).await?;
+                return Ok(());
+            }
+        },
+        None => now,
+    };
+
+    let (_file_path, file, data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), true).await?;
+    file.unlock()?;
+
+    let clock = FixedClock(at);
+    let count = count.unwrap_or(5).clamp(1, 20);
+
+    let closed = data.is_currently_closed(&clock);
+    let cause = data.mode_cause(&clock);
+    let transitions = data.transitions(&clock, count);
+
+    ctx.send(|b| b.embed(|embed| {
+        embed.color(if closed {serenity::colours::branding::RED} else {serenity::colours::branding::GREEN});
+        embed.title(format!("Schedule at <t:{}:f>", clock.0));
+        embed.field("Whitelist status", if closed {"Closed"} else {"Open"}, false);
+        let label_s = data.cause_label(&cause).map(|x| format!(" ({x})")).unwrap_or_default();
+        embed.field("Cause", format!("{}{label_s}", cause.describe()), false);
+
+        if transitions.is_empty() {
+            embed.field("Upcoming changes", "No scheduled changes", false);
+        }
+        for transition in transitions {
+            let title = format!("{} <t:{}:f>", if transition.closed {"Closes"} else {"Opens"}, transition.at);
+            let label_s = transition.label.map(|x| format!(" ({x})")).unwrap_or_default();
+            embed.field(title, format!("{}{label_s}", transition.cause.describe()), false);
+        }
+        embed
+    })).await?;
+
+    Ok(())
+}
+
+/// Check the schedule for events that never fire, never change anything or overlap
+#[poise::command(slash_command, check = "channel_check")]
+pub async fn lint(
+    ctx: Context<'_>,
+) -> Result<(), Error> {
+    let (_file_path, file, data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), true).await?;
+    file.unlock()?;
+
+    let warnings = lint_schedule(&data, &SystemClock);
+
+    ctx.send(|b| b.embed(|embed| {
+        embed.color(if warnings.is_empty() {serenity::colours::branding::GREEN} else {serenity::colours::branding::YELLOW});
+        embed.title("Schedule check");
+        if warnings.is_empty() {
+            embed.description("No problems found");
+        }
+        //Embeds are limited to 25 fields.
+        for (i, warning) in warnings.iter().take(25).enumerate() {
+            embed.field(format!("Warning {}", i + 1), warning.describe(), false);
+        }
+        embed
+    })).await?;
+
+    Ok(())
+}
+
+/// Show when the whitelist status changed, or what it was at a point in time
+#[poise::command(slash_command, check = "channel_check")]
+pub async fn history(
+    ctx: Context<'_>,
+    #[description = "Time to look up, e.g. a Unix timestamp or `2024-05-03T22:00` in the server's timezone, defaults to listing recent changes"]
+    at: Option<String>,
+    #[description = "How many recent changes to list, defaults to 10"]
+    count: Option<usize>,
+) -> Result<(), Error> {
+    let tz = load_timezone().await?;
+    let at = match at.map(|x| parse_time(&x, &tz, chrono::Utc::now().timestamp())).transpose() {
+        Ok(at) => at,
+        Err(e) => {
+            ctx.say(e).await?;
+            return Ok(());
+        }
+    };
+
+    let (_file_path, file, log) = load_json::<HistoryLog>(Some(&ctx),"history.json".to_string(), true).await?;
+    file.unlock()?;
+
+    let describe_entry = |entry: &HistoryEntry| {
+        let label_s = entry.label.as_ref().map(|x| format!(" ({x})")).unwrap_or_default();
+        format!("{}{label_s}, {}", entry.cause.describe(), entry.reason.describe())
+    };
+
+    let Some(at) = at else {
+        let count = count.unwrap_or(10).clamp(1, 20);
+        ctx.send(|b| b.embed(|embed| {
+            embed.color(serenity::colours::branding::BLURPLE);
+            embed.title("Recent status changes");
+            if log.entries.is_empty() {
+                embed.description("No changes recorded yet");
+            }
+            for entry in log.entries.iter().rev().take(count) {
+                let title = format!("{} <t:{}:f>", if entry.closed {"Closed"} else {"Opened"}, entry.at);
+                embed.field(title, describe_entry(entry), false);
+            }
+            embed
+        })).await?;
+        return Ok(());
+    };
+
+    let Some(entry) = log.status_at(at) else {
+        match log.entries.first() {
+            Some(first) => ctx.say(format!("No history recorded for <t:{at}:f>, it starts at <t:{}:f>", first.at)).await?,
+            None => ctx.say("No changes recorded yet").await?,
+        };
+        return Ok(());
+    };
+    let until = log.between(at.saturating_add(1), i64::MAX).first().map(|x| x.at);
+
+    ctx.send(|b| b.embed(|embed| {
+        embed.color(if entry.closed {serenity::colours::branding::RED} else {serenity::colours::branding::GREEN});
+        embed.title(format!("Status at <t:{at}:f>"));
+        embed.field("Whitelist status", if entry.closed {"Closed"} else {"Open"}, false);
+        embed.field("Cause", describe_entry(entry), false);
+        let until_s = until.map(|x| format!(" until <t:{x}:f>")).unwrap_or(", still in effect".to_string());
+        embed.field("In effect", format!("From <t:{}:f>{until_s}", entry.at), false);
+        embed
+    })).await?;
+
+    Ok(())
+}
+
+/// Admin only command to explain why a Resonite UserID can or can't join the headless
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn whycantjoin(
+    ctx: Context<'_>,
+    #[rest]
+    #[description = "Resonite UserID"]
+    uid: String,
+) -> Result<(), Error> {
+    let snapshot = WhitelistSnapshot::load().await?;
+    let explanation = snapshot.explain(&uid);
+
+    let yes_no = |x: bool| if x {"Yes"} else {"No"};
+    let registered_str = if explanation.registered_by.is_empty() {"Not registered".to_string()} else {
+        explanation.registered_by.iter().map(|x| format!("<@{x}>")).collect::<Vec<String>>().join(", ")
+    };
+    let decision = explanation.decision;
+    let decision_str = format!("{} ({})", if decision.whitelisted {"Can join"} else {"Can't join"}, decision.reason.describe());
+
+    ctx.send(|b| b.allowed_mentions(|b| b.empty_roles().empty_users()).embed(|embed| {
+        embed.color(if decision.whitelisted {serenity::colours::branding::GREEN} else {serenity::colours::branding::RED});
+        embed.title(format!("Whitelist decision for {uid}"));
+        embed.field("Effective mode", explanation.cause.describe(), false);
+        embed.field("In admin whitelist", yes_no(explanation.in_admin_whitelist), true);
+        embed.field("In closed whitelist", yes_no(explanation.in_closed_whitelist), true);
+        embed.field("Registered by", registered_str, false);
+        embed.field("Decision", decision_str, false);
+        embed
+    })).await?;
+
+    Ok(())
+}
+
 /// Gets the Resonite UserID from a given username.
 #[poise::command(slash_command, check = "channel_check")]
 pub async fn userid(
@@ -121,11 +330,27 @@ pub async fn help(ctx: Context<'_>, command: Option<String>) -> Result<(), Error
 }
 
 
+/// Periodically moves finished schedule events to the archive and removes expired temporary whitelist entries.
+async fn archive_loop(scheduler: Scheduler) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        match archive_expired_events().await {
+            Ok(0) => (),
+            Ok(_) => scheduler.schedule_changed(),
+            Err(e) => println!("archive_expired_events:{e:?}"),
+        }
+        if let Err(e) = purge_expired_entries().await {
+            println!("purge_expired_entries:{e:?}");
+        }
+    }
+}
+
 #[tokio::main]
-pub async fn discord() {
+pub async fn discord(scheduler: Scheduler) {
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![register(),userid(),status(),help(),setchannel(),addrole(),removerole(),adduser(),removeuser(),adduserclosed(),removeuserclosed(),setclosed(),addcloseevent(),removecloseevent(),addopenevent(),removeopenevent(),listevents(),setinfourl(),checkregistered()],
+            commands: vec![register(),userid(),status(),help(),setchannel(),addrole(),removerole(),adduser(),removeuser(),adduserclosed(),removeuserclosed(),listtemporary(),setclosed(),addcloseevent(),removecloseevent(),addopenevent(),addclosecron(),addopencron(),addcloserrule(),addopenrrule(),addclosedwindow(),removeclosedwindow(),removeopenevent(),listevents(),listarchived(),editevent(),pauseevent(),resumeevent(),skipdate(),unskipdate(),addholiday(),removeholiday(),listholidays(),setinfourl(),settimezone(),checkregistered(),whycantjoin(),schedule(),history(),announce(),guildevents(),importcalendar()],
             ..Default::default()
         })
         .token(std::env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN"))
@@ -133,7 +358,10 @@ pub async fn discord() {
         .setup(|ctx, _ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
-                Ok(Data {})
+                tokio::spawn(archive_loop(scheduler.clone()));
+                tokio::spawn(announce_loop(ctx.http.clone(), scheduler.clone()));
+                tokio::spawn(guild_events_loop(ctx.http.clone(), scheduler.clone()));
+                Ok(Data {scheduler})
             })
         });
 
@@ -5,6 +5,7 @@ use poise::serenity_prelude as serenity;
 use super::common::GeneralData;
 use super::checks::admin_check;
 use crate::commonio::*;
+use crate::repeat::parse_timezone;
 
 /// Admin only command to set channel for bot to be used in.
 #[poise::command(slash_command, check = "admin_check")]
@@ -19,7 +20,7 @@ pub async fn setchannel(
     
     data.channel_id = Some(channelid);
 
-    write_tmp_and_copy(&ctx, &file_path, file, &serde_json::to_string(&data)?).await?;
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
 
     ctx.say(format!("Changed channel to <#{}>",channelid)).await?;
     Ok(())
@@ -43,14 +44,14 @@ pub async fn addrole (
             return Ok(());
         }
         roleids.push(newroleid);
-        write_tmp_and_copy(&ctx, &file_path, file,&serde_json::to_string(&data)?).await?;
+        write_tmp_and_copy(Some(&ctx), &file_path, file,&serde_json::to_string(&data)?).await?;
         
         //file.unlock()?;
         ctx.say("Role has been added to admin roles.").await?;
         return Ok(());
     }
     data.admin_roles = Some(vec![newroleid]);
-    write_tmp_and_copy(&ctx, &file_path, file, &serde_json::to_string(&data)?).await?;
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
     //file.unlock()?;
     ctx.say("Role has been added to admin roles.").await?;
     Ok(())
@@ -71,7 +72,7 @@ pub async fn removerole (
         if roleids.contains(&newroleid) {
             let index = roleids.iter().position(|x| *x == newroleid).unwrap();
             roleids.remove(index);
-            write_tmp_and_copy(&ctx, &file_path, file, &serde_json::to_string(&data)?).await?;
+            write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
             ctx.say("Role has been removed from admins").await?;
             return Ok(());
         }
@@ -96,10 +97,40 @@ pub async fn setinfourl (
 
     data.info_api = url.clone();
 
-    write_tmp_and_copy(&ctx, &file_path, file, &serde_json::to_string(&data)?).await?;
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
 
     let to_say = if let Some(val) = url {format!("Successfully set info URL to {val}")} else {"Successfuly unset info URL".to_string()};
 
     ctx.say(to_say).await?;
     Ok(())
-}
\ No newline at end of file
+}
+/// Admin only command to set the timezone times are entered in and new events repeat in
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn settimezone (
+    ctx: Context<'_>,
+    #[rest]
+    #[description = "IANA timezone like `Europe/Berlin`, leave empty for UTC"]
+    timezone: Option<String>
+) -> Result<(),Error> {
+    let timezone = match timezone.as_deref().map(parse_timezone).transpose() {
+        Ok(tz) => tz.map(|x| x.name().to_string()),
+        Err(e) => {
+            ctx.say(e).await?;
+            return Ok(());
+        }
+    };
+
+    let (file_path, file, mut data) = load_json::<GeneralData>(Some(&ctx),"data.json".to_string(), false).await?;
+
+    data.timezone = timezone.clone();
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    let to_say = match timezone {
+        Some(val) => format!("Successfully set timezone to {val}, existing events keep the timezone they were added with"),
+        None => "Successfully set timezone to UTC".to_string(),
+    };
+
+    ctx.say(to_say).await?;
+    Ok(())
+}
@@ -0,0 +1,272 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use fs4::tokio::AsyncFileExt;
+
+use poise::serenity_prelude as serenity;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::checks::admin_check;
+use super::common::{Announcements, GeneralData};
+use crate::clock::{Clock, SystemClock};
+use crate::commonio::*;
+use crate::scheduler::Scheduler;
+
+//How late a warning can still be posted, for when the bot was busy or restarting at the time.
+const WARNING_GRACE_SECONDS: i64 = 60;
+//Re-read the schedule at least this often while waiting for the next warning.
+const MAX_WARNING_SLEEP_SECONDS: i64 = 60;
+//A week, further ahead than any schedule change is worth warning about.
+const MAX_WARNING_MINUTES: i64 = 7 * 24 * 60;
+
+#[derive(Debug, poise::ChoiceParameter, Clone, Copy)]
+pub enum AnnouncementKind {
+    Close,
+    Open,
+    Warning,
+}
+
+/// Fills in a template's placeholders for a transition, with `minutes` until it for warnings.
+fn render(template: &str, transition: &Transition, minutes: Option<i64>) -> String {
+    let at = transition.at;
+    template
+        .replace("{status}", if transition.closed {"closed"} else {"open"})
+        .replace("{action}", if transition.closed {"closes"} else {"opens"})
+        .replace("{time}", &format!("<t:{at}:t>"))
+        .replace("{relative}", &format!("<t:{at}:R>"))
+        .replace("{minutes}", &minutes.unwrap_or(0).to_string())
+        .replace("{label}", transition.label.as_deref().unwrap_or_default())
+        .replace("{cause}", &transition.cause.describe())
+}
+
+async fn post(http: &serenity::Http, announcements: &Announcements, text: String) -> Result<(), Error> {
+    let Some(channel_id) = announcements.channel_id else {
+        return Ok(());
+    };
+    let content = match announcements.ping_role {
+        Some(role) => format!("<@&{role}> {text}"),
+        None => text,
+    };
+    serenity::ChannelId(channel_id).send_message(http, |m| {
+        m.content(content).allowed_mentions(|a| a.roles(announcements.ping_role.map(serenity::RoleId)))
+    }).await?;
+    Ok(())
+}
+
+async fn load_announcements() -> Result<Announcements, Error> {
+    let (_file_path, file, data) = load_json::<GeneralData>(None, "data.json".to_string(), true).await?;
+    file.unlock()?;
+    Ok(data.announcements)
+}
+
+async fn announce_transition(http: &serenity::Http, transition: &Transition) -> Result<(), Error> {
+    let announcements = load_announcements().await?;
+    let template = if transition.closed {&announcements.close_template} else {&announcements.open_template};
+    post(http, &announcements, render(template, transition, None)).await
+}
+
+/// Posts any warnings that are due before the next status change, returning how many seconds until the next one is.
+async fn post_due_warnings(http: &serenity::Http, sent: &mut HashSet<(i64, i64)>) -> Result<i64, Error> {
+    let announcements = load_announcements().await?;
+    if announcements.channel_id.is_none() || announcements.warning_minutes.is_empty() {
+        return Ok(MAX_WARNING_SLEEP_SECONDS);
+    }
+
+    let (_file_path, file, data) = load_json::<ClosedData>(None, "closed.json".to_string(), true).await?;
+    file.unlock()?;
+
+    let now = SystemClock.now();
+    sent.retain(|(at, _)| *at > now);
+    let Some(transition) = data.transitions(&SystemClock, 1).into_iter().next() else {
+        return Ok(MAX_WARNING_SLEEP_SECONDS);
+    };
+
+    let mut sleep_for = MAX_WARNING_SLEEP_SECONDS;
+    for minutes in &announcements.warning_minutes {
+        let Some(warn_at) = minutes.checked_mul(60).and_then(|x| transition.at.checked_sub(x)) else {
+            continue;
+        };
+        if warn_at > now {
+            sleep_for = sleep_for.min(warn_at - now);
+            continue;
+        }
+        if now - warn_at < WARNING_GRACE_SECONDS && sent.insert((transition.at, *minutes)) {
+            post(http, &announcements, render(&announcements.warning_template, &transition, Some(*minutes))).await?;
+        }
+    }
+    Ok(sleep_for)
+}
+
+/// Posts announcements for every transition from the scheduler, and advance warnings before each one.
+pub async fn announce_loop(http: Arc<serenity::Http>, scheduler: Scheduler) {
+    let mut transitions = scheduler.subscribe();
+    //Warnings already posted, by transition time and minutes before it.
+    let mut sent: HashSet<(i64, i64)> = HashSet::new();
+
+    loop {
+        let sleep_for = match post_due_warnings(&http, &mut sent).await {
+            Ok(sleep_for) => sleep_for,
+            Err(e) => {
+                println!("post_due_warnings:{e:?}");
+                MAX_WARNING_SLEEP_SECONDS
+            },
+        };
+
+        tokio::select! {
+            received = transitions.recv() => match received {
+                Ok(transition) => {
+                    if let Err(e) = announce_transition(&http, &transition).await {
+                        println!("announce_transition:{e:?}");
+                    }
+                },
+                Err(RecvError::Lagged(_)) => (),
+                Err(RecvError::Closed) => return,
+            },
+            _ = tokio::time::sleep(Duration::from_secs(sleep_for.max(1) as u64)) => (),
+        }
+    }
+}
+
+/// Admin only commands to configure announcements when the headless opens or closes
+#[poise::command(slash_command, subcommands("announcechannel", "announcetemplate", "announcewarnings", "announcerole", "announcesettings"), check = "admin_check")]
+pub async fn announce(
+    _ctx: Context<'_>,
+) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Set the channel announcements are posted in, or leave empty to stop posting
+#[poise::command(slash_command, rename = "channel", check = "admin_check")]
+pub async fn announcechannel(
+    ctx: Context<'_>,
+    #[description = "Channel"]
+    channel: Option<serenity::GuildChannel>,
+) -> Result<(), Error> {
+    let (file_path, file, mut data) = load_json::<GeneralData>(Some(&ctx),"data.json".to_string(), false).await?;
+
+    data.announcements.channel_id = channel.map(|x| x.id.0);
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    match data.announcements.channel_id {
+        Some(channel_id) => ctx.say(format!("Announcements will be posted in <#{channel_id}>")).await?,
+        None => ctx.say("Announcements turned off").await?,
+    };
+    Ok(())
+}
+
+/// Change the message posted when the headless closes, opens, or is about to
+#[poise::command(slash_command, rename = "template", check = "admin_check")]
+pub async fn announcetemplate(
+    ctx: Context<'_>,
+    #[description = "Which message to change"]
+    kind: AnnouncementKind,
+    #[description = "Message, can use {status} {action} {time} {relative} {minutes} {label} {cause}, `default` to reset"]
+    template: String,
+) -> Result<(), Error> {
+    let (file_path, file, mut data) = load_json::<GeneralData>(Some(&ctx),"data.json".to_string(), false).await?;
+
+    let defaults = Announcements::default();
+    let reset = template.trim().eq_ignore_ascii_case("default");
+    let (field, default) = match kind {
+        AnnouncementKind::Close => (&mut data.announcements.close_template, defaults.close_template),
+        AnnouncementKind::Open => (&mut data.announcements.open_template, defaults.open_template),
+        AnnouncementKind::Warning => (&mut data.announcements.warning_template, defaults.warning_template),
+    };
+    *field = if reset {default} else {template.trim().to_string()};
+    let new_template = field.clone();
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    ctx.say(format!("{kind} announcement is now: {new_template}")).await?;
+    Ok(())
+}
+
+/// Set how many minutes before each change to post a warning
+#[poise::command(slash_command, rename = "warnings", check = "admin_check")]
+pub async fn announcewarnings(
+    ctx: Context<'_>,
+    #[description = "Comma separated minutes, e.g. `60,15`, or `none` for no warnings"]
+    minutes: String,
+) -> Result<(), Error> {
+    let mut warning_minutes: Vec<i64> = Vec::new();
+    if !minutes.trim().eq_ignore_ascii_case("none") {
+        for value in minutes.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            match value.parse::<i64>() {
+                Ok(val) if val > 0 && val <= MAX_WARNING_MINUTES => warning_minutes.push(val),
+                Ok(val) if val > 0 => {
+                    ctx.say(format!("Warnings can be at most {MAX_WARNING_MINUTES} minutes (a week) before a change")).await?;
+                    return Ok(());
+                },
+                _ => {
+                    ctx.say(format!("Invalid number of minutes `{value}`")).await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+    warning_minutes.sort_unstable_by(|a, b| b.cmp(a));
+    warning_minutes.dedup();
+
+    let (file_path, file, mut data) = load_json::<GeneralData>(Some(&ctx),"data.json".to_string(), false).await?;
+
+    data.announcements.warning_minutes = warning_minutes.clone();
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    if warning_minutes.is_empty() {
+        ctx.say("No warnings will be posted").await?;
+    } else {
+        let list = warning_minutes.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
+        ctx.say(format!("Warnings will be posted {list} minutes before each change")).await?;
+    }
+    Ok(())
+}
+
+/// Set a role to ping with announcements, or leave empty to stop pinging
+#[poise::command(slash_command, rename = "role", check = "admin_check")]
+pub async fn announcerole(
+    ctx: Context<'_>,
+    #[description = "Role"]
+    role: Option<serenity::Role>,
+) -> Result<(), Error> {
+    let (file_path, file, mut data) = load_json::<GeneralData>(Some(&ctx),"data.json".to_string(), false).await?;
+
+    data.announcements.ping_role = role.map(|x| x.id.0);
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    match data.announcements.ping_role {
+        Some(role) => ctx.send(|b| b.content(format!("Announcements will ping <@&{role}>")).allowed_mentions(|a| a.empty_roles())).await?,
+        None => ctx.say("Announcements won't ping a role").await?,
+    };
+    Ok(())
+}
+
+/// Show the current announcement settings
+#[poise::command(slash_command, rename = "settings", check = "admin_check")]
+pub async fn announcesettings(
+    ctx: Context<'_>,
+) -> Result<(), Error> {
+    let announcements = load_announcements().await?;
+
+    let channel_s = announcements.channel_id.map(|x| format!("<#{x}>")).unwrap_or("Not set, announcements are off".to_string());
+    let role_s = announcements.ping_role.map(|x| format!("<@&{x}>")).unwrap_or("None".to_string());
+    let warnings_s = if announcements.warning_minutes.is_empty() {"None".to_string()} else {
+        announcements.warning_minutes.iter().map(|x| format!("{x} minutes")).collect::<Vec<String>>().join(", ")
+    };
+
+    ctx.send(|b| b.embed(|embed| {
+        embed.color(serenity::colours::branding::BLURPLE);
+        embed.title("Announcements");
+        embed.field("Channel", channel_s, false);
+        embed.field("Role ping", role_s, false);
+        embed.field("Warnings before changes", warnings_s, false);
+        embed.field("Close message", &announcements.close_template, false);
+        embed.field("Open message", &announcements.open_template, false);
+        embed.field("Warning message", &announcements.warning_template, false);
+        embed
+    })).await?;
+    Ok(())
+}
@@ -1,23 +1,45 @@
+use chrono::{NaiveDate, Utc};
 use fs4::tokio::AsyncFileExt;
 use tokio::io::{BufReader, AsyncBufReadExt};
 
 use crate::commonio::*;
 use crate::repeat::*;
+use crate::clock::SystemClock;
+use crate::cron::CronSchedule;
+use crate::lint::lint_schedule;
+use crate::timeparse::{parse_expiry, parse_time};
 use super::checks::*;
-use super::common::check_userid;
+use super::common::{check_userid, expiry_suffix, load_timezone, set_expiry};
 
 /// Admin only command to add users to the closed whitelist
 #[poise::command(slash_command, check = "admin_check")]
 pub async fn adduserclosed(
     ctx: Context<'_>,
-    #[rest]
     #[description = "Resonite UserID"]
     uid: String,
+    #[rename = "for"]
+    #[description = "Remove them again after this long, e.g. `4h` or `1d`, leave empty to keep them"]
+    duration: Option<String>,
+    #[description = "Remove them again at this time, e.g. `tomorrow 2am` in the server's timezone"]
+    until: Option<String>,
 ) -> Result<(),Error> {
+    let tz = load_timezone().await?;
+    let expires_at = match parse_expiry(duration.as_deref(), until.as_deref(), &tz, Utc::now().timestamp()) {
+        Ok(expires_at) => expires_at,
+        Err(e) => {
+            ctx.say(e).await?;
+            return Ok(());
+        }
+    };
     if !check_userid(&ctx, &uid).await? {
         return Ok(());
     }
 
+    //Lock the expiries and revisions before the list, in the same order as purging expired entries does.
+    let (expiries_path, expiries_file, mut expiries) = load_json::<WhitelistExpiries>(Some(&ctx), "expiries.json".to_string(), false).await?;
+    let revisions = lock_revisions(Some(&ctx)).await?;
+    let previous = expiries.expires_at(WhitelistName::Closed, &uid);
+
     let dir = get_dir()?;
     let file_path = dir.join("usersclosed.txt");
 
@@ -34,19 +56,34 @@ pub async fn adduserclosed(
     }
 
     if lines.contains(&uid) {
-        ctx.say("User is already in admin whitelist").await?;
         file.unlock()?;
+        revisions.release()?;
+        if expires_at.is_some() && previous.is_none() {
+            expiries_file.unlock()?;
+            ctx.say("User is already permanently in closed whitelist, remove them first to add them temporarily").await?;
+            return Ok(());
+        }
+        expiries.set(WhitelistName::Closed, &uid, expires_at);
+        write_tmp_and_copy(Some(&ctx), &expiries_path, expiries_file, &serde_json::to_string(&expiries)?).await?;
+        match (expires_at, previous) {
+            (Some(_), _) => ctx.say(format!("User is already in closed whitelist{}", expiry_suffix(expires_at))).await?,
+            (None, Some(_)) => ctx.say("User is already in closed whitelist, it will now be kept").await?,
+            (None, None) => ctx.say("User is already in closed whitelist").await?,
+        };
         return Ok(());
     }
 
     lines.push(uid.clone());
 
-    
+    //Write the expiry first, so a temporary entry is never in the list without it.
+    expiries.set(WhitelistName::Closed, &uid, expires_at);
+    write_tmp_and_copy(Some(&ctx), &expiries_path, expiries_file, &serde_json::to_string(&expiries)?).await?;
 
     let new_contents = lines.join("\n");
-    match write_tmp_and_copy(&ctx, &file_path, file, &new_contents).await {
+    match write_tmp_and_copy(Some(&ctx), &file_path, file, &new_contents).await {
         Ok(_) => {
-            ctx.say(format!("Successfully added record for {uid}!")).await?;
+            revisions.record(Some(&ctx), vec![Change::Added{list: WhitelistName::Closed, uid: uid.clone()}]).await?;
+            ctx.say(format!("Successfully added record for {uid}{}!", expiry_suffix(expires_at))).await?;
             return Ok(());
         }
         Err(e) => {
@@ -64,6 +101,7 @@ pub async fn removeuserclosed(
     #[description = "Resonite UserID"]
     uid: String,
 ) -> Result<(),Error> {
+    let revisions = lock_revisions(Some(&ctx)).await?;
     let dir = get_dir()?;
     let file_path = dir.join("usersclosed.txt");
 
@@ -80,6 +118,7 @@ pub async fn removeuserclosed(
     }
 
     if !lines.contains(&uid) {
+        revisions.release()?;
         ctx.say("UserID was not in admin whitelist").await?;
         return Ok(());
     }
@@ -88,8 +127,10 @@ pub async fn removeuserclosed(
     lines.remove(index);
 
     let new_contents = lines.join("\n");
-    match write_tmp_and_copy(&ctx, &file_path, file, &new_contents).await {
+    match write_tmp_and_copy(Some(&ctx), &file_path, file, &new_contents).await {
         Ok(_) => {
+            revisions.record(Some(&ctx), vec![Change::Removed{list: WhitelistName::Closed, uid: uid.clone()}]).await?;
+            set_expiry(&ctx, WhitelistName::Closed, &uid, None).await?;
             ctx.say(format!("Successfully added record for {uid}!")).await?;
             return Ok(());
         }
@@ -100,19 +141,39 @@ pub async fn removeuserclosed(
     }
 }
 
-/// Admin only command to set the headless to closed mode.
+/// Admin only command to set the headless to closed mode, optionally only for a while
 #[poise::command(slash_command, check = "admin_check")]
 pub async fn setclosed(
     ctx: Context<'_>,
-    #[rest]
     #[description = "Closed"]
     closed: ClosedStatus,
+    #[rename = "for"]
+    #[description = "Go back to automatic after this long, e.g. `2h`, `90m` or `1d`"]
+    duration: Option<String>,
+    #[description = "Go back to automatic at this time, e.g. `20:00` or `tomorrow 8am` in the server's timezone"]
+    until: Option<String>,
 ) -> Result<(),Error> {
+    let tz = load_timezone().await?;
+    let until = match parse_expiry(duration.as_deref(), until.as_deref(), &tz, Utc::now().timestamp()) {
+        Ok(until) => until,
+        Err(e) => {
+            ctx.say(e).await?;
+            return Ok(());
+        }
+    };
+    if until.is_some() && matches!(closed, ClosedStatus::Automatic) {
+        ctx.say("Automatic is already the default, `for` and `until` only apply to Open or Closed").await?;
+        return Ok(());
+    }
+
+    let revisions = lock_revisions(Some(&ctx)).await?;
     let (file_path, file, mut data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), false).await?;
     
     data.is_closed = closed;
+    data.override_until = until;
 
-    write_tmp_and_copy(&ctx, &file_path, file, &serde_json::to_string(&data)?).await?;
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+    revisions.record(Some(&ctx), vec![Change::Mode{status: closed, until}]).await?;
     
     let to_say = match closed {
         ClosedStatus::Open => "Open",
@@ -120,71 +181,402 @@ pub async fn setclosed(
         ClosedStatus::Automatic => "Automatic",
     };
 
-    ctx.say(format!("Set headless to {to_say}")).await?;
+    match until {
+        Some(until) => ctx.say(format!("Set headless to {to_say} until <t:{until}:f> (<t:{until}:R>), then back to Automatic")).await?,
+        None => ctx.say(format!("Set headless to {to_say}")).await?,
+    };
+    Ok(())
+}
+
+
+
+/// Options every added event has besides when it starts and how it repeats.
+struct EventOptions {
+    timezone: Option<String>,
+    until: Option<String>,
+    count: Option<u32>,
+    label: Option<String>,
+    description: Option<String>,
+}
+
+/// Builds a new event from the options the add commands share, telling the user and returning None if any are invalid.
+///
+/// Times are read in the given timezone, or the server's if there is none. Without a start the event starts now, as cron events do.
+async fn build_event(ctx: &Context<'_>, start: Option<&str>, repeating: Recurrence, options: EventOptions) -> Result<Option<RepeatingEvent>, Error> {
+    if let Some(Err(e)) = options.timezone.as_deref().map(parse_timezone) {
+        ctx.say(e).await?;
+        return Ok(None);
+    }
+    if options.count.is_some_and(|x| x > MAX_COUNT) {
+        ctx.say(format!("Count can be at most {MAX_COUNT}")).await?;
+        return Ok(None);
+    }
+    let timezone = timezone_or_default(options.timezone).await?;
+    let initial = match start {
+        Some(start) => match parse_start(ctx, start, timezone.as_deref()).await? {
+            Some(initial) => initial,
+            None => return Ok(None),
+        },
+        None => Utc::now().timestamp(),
+    };
+    let Some(until) = parse_optional_time(ctx, options.until.as_deref(), timezone.as_deref()).await? else {
+        return Ok(None);
+    };
+
+    let mut event = new_event(&ctx.author().id.to_string(), initial, repeating, timezone, options.label, options.description);
+    event.until = until;
+    event.count = options.count;
+    Ok(Some(event))
+}
+
+/// An event added by `owner`, as both the add commands and calendar imports make them.
+pub(super) fn new_event(owner: &str, initial: i64, repeating: Recurrence, timezone: Option<String>, label: Option<String>, description: Option<String>) -> RepeatingEvent {
+    let mut event = RepeatingEvent::new(initial, repeating, timezone);
+    event.label = label;
+    event.description = description;
+    event.owner = Some(owner.to_string());
+    return event;
+}
+
+/// Adds an event with the next free id for its kind, returning the id, when it next fires and any lint warnings to follow the confirmation.
+///
+/// Closed windows need `duration`, the other kinds ignore it.
+async fn insert_event(ctx: &Context<'_>, kind: EventKind, mut event: RepeatingEvent, duration: i64) -> Result<(usize, i64, String), Error> {
+    let (file_path, file, mut data) = load_json::<ClosedData>(Some(ctx),"closed.json".to_string(), false).await?;
+
+    let current_id = data.next_id(kind);
+    event.id = current_id;
+    let next = event.next(Utc::now().timestamp(), &data.holidays);
+
+    match kind {
+        EventKind::Close => {
+            data.close_events.insert(current_id, event);
+        },
+        EventKind::Open => {
+            data.open_events.insert(current_id, event);
+        },
+        EventKind::Window => {
+            data.closed_windows.insert(current_id, ClosedWindow{id: current_id, start: event, duration});
+        },
+    }
+    let warnings = lint_suffix(&data, kind, current_id);
+
+    write_tmp_and_copy(Some(ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+    Ok((current_id, next, warnings))
+}
+
+/// Close or open, for confirmation messages.
+fn verb(kind: EventKind) -> &'static str {
+    if let EventKind::Open = kind {"open"} else {"close"}
+}
+
+async fn add_interval_event(ctx: Context<'_>, kind: EventKind, timestamp: String, n: Option<i64>, t: Option<RepeatType>, options: EventOptions) -> Result<(), Error> {
+    let repeating = match interval_or_once(n, t) {
+        Ok(repeating) => repeating,
+        Err(e) => {
+            ctx.say(e).await?;
+            return Ok(());
+        }
+    };
+    let Some(event) = build_event(&ctx, Some(&timestamp), repeating, options).await? else {
+        return Ok(());
+    };
+    let (describe, start) = (event.describe(), event.initial);
+    let (_id, _next, warnings) = insert_event(&ctx, kind, event, 0).await?;
+
+    ctx.say(format!("Added event to {} {describe} starting on <t:{start}:F> (<t:{start}:R>, parsed from `{timestamp}`){warnings}", verb(kind))).await?;
     Ok(())
 }
 
+async fn add_cron_event(ctx: Context<'_>, kind: EventKind, expression: String, options: EventOptions) -> Result<(), Error> {
+    if let Err(e) = CronSchedule::parse(&expression) {
+        ctx.say(format!("Invalid cron expression: {e}")).await?;
+        return Ok(());
+    }
+    let Some(event) = build_event(&ctx, None, Recurrence::Cron{cron: expression}, options).await? else {
+        return Ok(());
+    };
+    let describe = event.describe();
+    let (_id, next, warnings) = insert_event(&ctx, kind, event, 0).await?;
+
+    let next_s = if next == i64::MAX {"never fires".to_string()} else {format!("next on <t:{next}:f>")};
+    ctx.say(format!("Added event to {} {describe}, {next_s}{warnings}", verb(kind))).await?;
+    Ok(())
+}
 
+async fn add_rrule_event(ctx: Context<'_>, kind: EventKind, rule: String, timestamp: String, exdates: Option<String>, options: EventOptions) -> Result<(), Error> {
+    let Some(mut event) = build_event(&ctx, Some(&timestamp), Recurrence::Once, options).await? else {
+        return Ok(());
+    };
+    let tz = event.tz();
+
+    let mut exdate_list: Vec<i64> = Vec::new();
+    for exdate in exdates.iter().flat_map(|x| x.split(',')).map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        match parse_time(exdate, &tz, Utc::now().timestamp()) {
+            Ok(val) => exdate_list.push(val),
+            Err(e) => {
+                ctx.say(format!("Invalid EXDATE `{exdate}`: {e}")).await?;
+                return Ok(());
+            }
+        }
+    }
 
-/// Admin only command to add a repeating time to automatically set the headless to closed
+    if let Err(e) = build_rrule_set(&rule, event.initial, &tz, &exdate_list) {
+        ctx.say(format!("Invalid RRULE: {e}")).await?;
+        return Ok(());
+    }
+    event.repeating = Recurrence::RRule{rrule: rule, exdates: exdate_list};
+
+    let (describe, start) = (event.describe(), event.initial);
+    let (_id, next, warnings) = insert_event(&ctx, kind, event, 0).await?;
+
+    let next_s = if next == i64::MAX {"never fires again".to_string()} else {format!("next on <t:{next}:f>")};
+    ctx.say(format!("Added event to {} {describe} starting on <t:{start}:F> (<t:{start}:R>, parsed from `{timestamp}`), {next_s}{warnings}", verb(kind))).await?;
+    Ok(())
+}
+
+/// Admin only command to add a repeating or one-off time to automatically set the headless to closed
 #[poise::command(slash_command, check = "admin_check")]
+#[allow(clippy::too_many_arguments)] //Each argument is a slash command option.
 pub async fn addcloseevent(
     ctx: Context<'_>,
     #[rest]
-    #[description = "Starting timestamp"]
-    timestamp: i64,
-    #[description = "Repeat every N"]
-    n: i64,
+    #[description = "Start time, e.g. a Unix timestamp, `2024-05-03T20:00` or `next friday 20:00`"]
+    timestamp: String,
+    #[description = "Repeat every N, leave empty for a one-off event"]
+    n: Option<i64>,
     #[description = "type"]
-    t: RepeatType,
+    t: Option<RepeatType>,
+    #[description = "IANA timezone for daily and longer repeats, defaults to the server's timezone"]
+    timezone: Option<String>,
+    #[description = "Stop repeating after this time, e.g. a Unix timestamp or `2024-12-31`"]
+    until: Option<String>,
+    #[description = "Stop repeating after this many occurrences"]
+    count: Option<u32>,
+    #[description = "Short name for the event, like `Friday members night`"]
+    label: Option<String>,
+    #[description = "Longer explanation of what the event is for"]
+    description: Option<String>,
 ) -> Result<(),Error> {
-    let (file_path, file, mut data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), false).await?;
-    
-    let current_id: usize = if data.close_events.is_empty() {0} else {
-        *data.close_events.keys().max().unwrap() + 1
-    };
+    let options = EventOptions{timezone, until, count, label, description};
+    return add_interval_event(ctx, EventKind::Close, timestamp, n, t, options).await;
+}
+
+/// Admin only command to add a repeating or one-off time to automatically set the headless to open
+#[poise::command(slash_command, check = "admin_check")]
+#[allow(clippy::too_many_arguments)] //Each argument is a slash command option.
+pub async fn addopenevent(
+    ctx: Context<'_>,
+    #[rest]
+    #[description = "Start time, e.g. a Unix timestamp, `2024-05-03T20:00` or `next friday 20:00`"]
+    timestamp: String,
+    #[description = "Repeat every N, leave empty for a one-off event"]
+    n: Option<i64>,
+    #[description = "type"]
+    t: Option<RepeatType>,
+    #[description = "IANA timezone for daily and longer repeats, defaults to the server's timezone"]
+    timezone: Option<String>,
+    #[description = "Stop repeating after this time, e.g. a Unix timestamp or `2024-12-31`"]
+    until: Option<String>,
+    #[description = "Stop repeating after this many occurrences"]
+    count: Option<u32>,
+    #[description = "Short name for the event, like `Friday members night`"]
+    label: Option<String>,
+    #[description = "Longer explanation of what the event is for"]
+    description: Option<String>,
+) -> Result<(),Error> {
+    let options = EventOptions{timezone, until, count, label, description};
+    return add_interval_event(ctx, EventKind::Open, timestamp, n, t, options).await;
+}
 
-    data.close_events.insert(current_id, RepeatingEvent{id: current_id, initial: timestamp, repeating: RepeatInterval{t,n}});
+/// Admin only command to add a cron schedule to automatically set the headless to closed
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn addclosecron(
+    ctx: Context<'_>,
+    #[description = "Cron expression, e.g. `0 20 * * FRI`"]
+    expression: String,
+    #[description = "IANA timezone for the expression, defaults to the server's timezone"]
+    timezone: Option<String>,
+    #[description = "Stop repeating after this time, e.g. a Unix timestamp or `2024-12-31`"]
+    until: Option<String>,
+    #[description = "Stop repeating after this many occurrences"]
+    count: Option<u32>,
+    #[description = "Short name for the event, like `Friday members night`"]
+    label: Option<String>,
+    #[description = "Longer explanation of what the event is for"]
+    description: Option<String>,
+) -> Result<(),Error> {
+    let options = EventOptions{timezone, until, count, label, description};
+    return add_cron_event(ctx, EventKind::Close, expression, options).await;
+}
 
-    write_tmp_and_copy(&ctx, &file_path, file, &serde_json::to_string(&data)?).await?;
-    
-    let type_s = t.with_plurality(n);
+/// Admin only command to add a cron schedule to automatically set the headless to open
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn addopencron(
+    ctx: Context<'_>,
+    #[description = "Cron expression, e.g. `0 20 * * FRI`"]
+    expression: String,
+    #[description = "IANA timezone for the expression, defaults to the server's timezone"]
+    timezone: Option<String>,
+    #[description = "Stop repeating after this time, e.g. a Unix timestamp or `2024-12-31`"]
+    until: Option<String>,
+    #[description = "Stop repeating after this many occurrences"]
+    count: Option<u32>,
+    #[description = "Short name for the event, like `Friday members night`"]
+    label: Option<String>,
+    #[description = "Longer explanation of what the event is for"]
+    description: Option<String>,
+) -> Result<(),Error> {
+    let options = EventOptions{timezone, until, count, label, description};
+    return add_cron_event(ctx, EventKind::Open, expression, options).await;
+}
+
+/// Admin only command to add an RFC 5545 RRULE to automatically set the headless to closed
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn addcloserrule(
+    ctx: Context<'_>,
+    #[description = "RRULE, e.g. `FREQ=MONTHLY;BYDAY=SA;BYSETPOS=2,4`"]
+    rule: String,
+    #[description = "Start time, e.g. a Unix timestamp, `2024-05-03T20:00` or `next friday 20:00` (DTSTART)"]
+    timestamp: String,
+    #[description = "IANA timezone for the rule, defaults to the server's timezone"]
+    timezone: Option<String>,
+    #[description = "Comma separated start times of occurrences to exclude, in the rule's timezone (EXDATE)"]
+    exdates: Option<String>,
+    #[description = "Short name for the event, like `Friday members night`"]
+    label: Option<String>,
+    #[description = "Longer explanation of what the event is for"]
+    description: Option<String>,
+) -> Result<(),Error> {
+    let options = EventOptions{timezone, until: None, count: None, label, description};
+    return add_rrule_event(ctx, EventKind::Close, rule, timestamp, exdates, options).await;
+}
+
+/// Admin only command to add an RFC 5545 RRULE to automatically set the headless to open
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn addopenrrule(
+    ctx: Context<'_>,
+    #[description = "RRULE, e.g. `FREQ=MONTHLY;BYDAY=SA;BYSETPOS=2,4`"]
+    rule: String,
+    #[description = "Start time, e.g. a Unix timestamp, `2024-05-03T20:00` or `next friday 20:00` (DTSTART)"]
+    timestamp: String,
+    #[description = "IANA timezone for the rule, defaults to the server's timezone"]
+    timezone: Option<String>,
+    #[description = "Comma separated start times of occurrences to exclude, in the rule's timezone (EXDATE)"]
+    exdates: Option<String>,
+    #[description = "Short name for the event, like `Friday members night`"]
+    label: Option<String>,
+    #[description = "Longer explanation of what the event is for"]
+    description: Option<String>,
+) -> Result<(),Error> {
+    let options = EventOptions{timezone, until: None, count: None, label, description};
+    return add_rrule_event(ctx, EventKind::Open, rule, timestamp, exdates, options).await;
+}
 
-    let to_say = format!("Added event to close every {n} {type_s} starting on <t:{timestamp}:f>");
-    ctx.say(to_say).await?;
+/// Admin only command to add a single or repeating window of time during which the headless is closed
+#[poise::command(slash_command, check = "admin_check")]
+#[allow(clippy::too_many_arguments)] //Each argument is a slash command option.
+pub async fn addclosedwindow(
+    ctx: Context<'_>,
+    #[description = "Start of the first window, e.g. a Unix timestamp, `2024-05-03T20:00` or `next friday 20:00`"]
+    timestamp: String,
+    #[description = "Minutes each window lasts"]
+    duration: i64,
+    #[description = "Repeat every N, leave empty for a single closed session"]
+    n: Option<i64>,
+    #[description = "type"]
+    t: Option<RepeatType>,
+    #[description = "IANA timezone for daily and longer repeats, defaults to the server's timezone"]
+    timezone: Option<String>,
+    #[description = "Stop repeating after this time, e.g. a Unix timestamp or `2024-12-31`"]
+    until: Option<String>,
+    #[description = "Stop repeating after this many windows"]
+    count: Option<u32>,
+    #[description = "Short name for the event, like `Friday members night`"]
+    label: Option<String>,
+    #[description = "Longer explanation of what the event is for"]
+    description: Option<String>,
+) -> Result<(),Error> {
+    if duration <= 0 {
+        ctx.say("Duration must be at least one minute").await?;
+        return Ok(());
+    }
+    let repeating = match interval_or_once(n, t) {
+        Ok(repeating) => repeating,
+        Err(e) => {
+            ctx.say(e).await?;
+            return Ok(());
+        }
+    };
+    let options = EventOptions{timezone, until, count, label, description};
+    let Some(event) = build_event(&ctx, Some(&timestamp), repeating, options).await? else {
+        return Ok(());
+    };
+    let start = event.initial;
+    let describe = ClosedWindow{id: 0, start: event.clone(), duration: duration * 60}.describe();
+    let (current_id, _next, warnings) = insert_event(&ctx, EventKind::Window, event, duration * 60).await?;
+
+    ctx.say(format!("Added window {current_id}, {describe} starting on <t:{start}:F> (<t:{start}:R>, parsed from `{timestamp}`){warnings}")).await?;
     Ok(())
 }
 
-/// Admin only command to add a repeating time to automatically set the headless to open
+/// Admin only command to remove a closed window
 #[poise::command(slash_command, check = "admin_check")]
-pub async fn addopenevent(
+pub async fn removeclosedwindow(
     ctx: Context<'_>,
     #[rest]
-    #[description = "Starting timestamp"]
-    timestamp: i64,
-    #[description = "Repeat every N"]
-    n: i64,
-    #[description = "type"]
-    t: RepeatType,
+    #[description = "id of window"]
+    id: usize,
 ) -> Result<(),Error> {
     let (file_path, file, mut data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), false).await?;
-    
-    let current_id: usize = if data.open_events.is_empty() {0} else {
-        *data.open_events.keys().max().unwrap() + 1
-    };
 
-    data.open_events.insert(current_id, RepeatingEvent{id: current_id, initial: timestamp, repeating: RepeatInterval{t,n}});
-    
+    if let Some(value) = data.closed_windows.remove(&id) {
+        let describe = value.describe();
+        let title = value.start.title();
 
-    write_tmp_and_copy(&ctx, &file_path, file, &serde_json::to_string(&data)?).await?;
-    
-    let type_s = t.with_plurality(n);
+        write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+        ctx.say(format!("Removed window {title}, {describe}")).await?;
+        return Ok(());
+    }
+    ctx.say(format!("No such closed window exists with id {id}")).await?;
+    file.unlock()?;
 
-    let to_say = format!("Added event to open every {n} {type_s} starting on <t:{timestamp}:f>");
-    ctx.say(to_say).await?;
     Ok(())
 }
 
+/// The timezone to store on a new event, the server's if none was given.
+async fn timezone_or_default(timezone: Option<String>) -> Result<Option<String>, Error> {
+    if timezone.is_some() {
+        return Ok(timezone);
+    }
+    let tz = load_timezone().await?;
+    Ok((tz != chrono_tz::UTC).then(|| tz.name().to_string()))
+}
+
+/// Parses a user entered start time in the event's timezone, or the server's if it has none, telling the user if it couldn't be understood.
+async fn parse_start(ctx: &Context<'_>, input: &str, timezone: Option<&str>) -> Result<Option<i64>, Error> {
+    let tz = match timezone.and_then(|x| parse_timezone(x).ok()) {
+        Some(tz) => tz,
+        None => load_timezone().await?,
+    };
+    match parse_time(input, &tz, Utc::now().timestamp()) {
+        Ok(start) => Ok(Some(start)),
+        Err(e) => {
+            ctx.say(e).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Parses an optional time like `parse_start`, giving `Some(None)` when there isn't one and `None` when the user was told it's invalid.
+async fn parse_optional_time(ctx: &Context<'_>, input: Option<&str>, timezone: Option<&str>) -> Result<Option<Option<i64>>, Error> {
+    match input {
+        Some(input) => Ok(parse_start(ctx, input, timezone).await?.map(Some)),
+        None => Ok(Some(None)),
+    }
+}
+
 /// Admin only command to remove an opening event
 #[poise::command(slash_command, check = "admin_check")]
 pub async fn removeopenevent(
@@ -197,14 +589,12 @@ pub async fn removeopenevent(
 
     if let Some(value) = data.open_events.remove(&id) {
 
-        let (t, n) = (value.repeating.t, value.repeating.n);
-        
-        let type_s = t.with_plurality(n);
-        let most_recent = value.most_recent();
+        let describe = value.describe();
+        let most_recent = value.most_recent(Utc::now().timestamp(), &data.holidays);
 
-        write_tmp_and_copy(&ctx, &file_path, file, &serde_json::to_string(&data)?).await?;
+        write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
 
-        let to_say = format!("Removed event {id} to open every {n} {type_s} with most recent at <t:{most_recent}:f>");
+        let to_say = format!("Removed event {} to open {describe} with most recent at <t:{most_recent}:f>", value.title());
         ctx.say(to_say).await?;
         return Ok(());
     }
@@ -226,14 +616,12 @@ pub async fn removecloseevent(
 
     if let Some(value) = data.close_events.remove(&id) {
 
-        let (t, n) = (value.repeating.t, value.repeating.n);
+        let describe = value.describe();
+        let most_recent = value.most_recent(Utc::now().timestamp(), &data.holidays);
 
-        let type_s = t.with_plurality(n);
-        let most_recent = value.most_recent();
+        write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
 
-        write_tmp_and_copy(&ctx, &file_path, file, &serde_json::to_string(&data)?).await?;
-
-        let to_say = format!("Removed event {id} to close every {n} {type_s} with most recent at <t:{most_recent}:f>");
+        let to_say = format!("Removed event {} to close {describe} with most recent at <t:{most_recent}:f>", value.title());
         ctx.say(to_say).await?;
         return Ok(());
     }
@@ -257,11 +645,9 @@ pub async fn listevents(
         .title("Open events");
 
         for event in data.open_events.values() {
-            let most_recent = event.most_recent();
-            let (t,n) = (event.repeating.t, event.repeating.n);
-            let type_s = t.with_plurality(n);
-            let val = format!("<t:{most_recent}:f> every {n} {type_s}");
-            embed.field(event.id, val, false);
+            let most_recent = event.most_recent(Utc::now().timestamp(), &data.holidays);
+            let val = format!("<t:{most_recent}:f> {}{}", event.describe(), event.details());
+            embed.field(event.title(), val, false);
         }
         embed
     })).await?;
@@ -271,14 +657,356 @@ pub async fn listevents(
         .title("Close events");
 
         for event in data.close_events.values() {
-            let most_recent = event.most_recent();
-            let (t,n) = (event.repeating.t, event.repeating.n);
-            let type_s = t.with_plurality(n);
-            let val = format!("<t:{most_recent}:f> every {n} {type_s}");
-            embed.field(event.id, val, false);
+            let most_recent = event.most_recent(Utc::now().timestamp(), &data.holidays);
+            let val = format!("<t:{most_recent}:f> {}{}", event.describe(), event.details());
+            embed.field(event.title(), val, false);
+        }
+        embed
+    })).await?;
+
+    ctx.send(|b| b.content("").embed(|embed| {
+        embed.color(poise::serenity_prelude::colours::branding::YELLOW)
+        .title("Closed windows");
+
+        for window in data.closed_windows.values() {
+            let most_recent = window.start.most_recent(Utc::now().timestamp(), &data.holidays);
+            let val = format!("<t:{most_recent}:f> {}{}", window.describe(), window.start.details());
+            embed.field(window.start.title(), val, false);
         }
         embed
     })).await?;
     Ok(())
 }
 
+
+
+/// Admin only command to show events that have finished and been archived
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn listarchived(
+    ctx: Context<'_>,
+) -> Result<(), Error> {
+    let (_file_path, _file, data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), true).await?;
+    _file.unlock()?;
+
+    //Embeds are limited to 25 fields, so only show the most recently archived.
+    let skip = data.archived_events.len().saturating_sub(25);
+
+    ctx.send(|b| b.content("").embed(|embed| {
+        embed.color(poise::serenity_prelude::colours::branding::BLURPLE)
+        .title("Archived events");
+
+        for archived in data.archived_events.iter().skip(skip).rev() {
+            let event = &archived.event;
+            let describe = match archived.duration {
+                Some(duration) => ClosedWindow{id: event.id, start: event.clone(), duration}.describe(),
+                None => event.describe(),
+            };
+            let val = format!("Started <t:{}:f> {describe}, archived <t:{}:f>{}", event.initial, archived.archived_at, event.details());
+            embed.field(format!("{:?} {}", archived.kind, event.title()), val, false);
+        }
+        embed
+    })).await?;
+    Ok(())
+}
+
+/// Admin only command to change an existing event while keeping its id
+#[poise::command(slash_command, check = "admin_check")]
+#[allow(clippy::too_many_arguments)] //Each argument is a slash command option.
+pub async fn editevent(
+    ctx: Context<'_>,
+    #[description = "Kind of event"]
+    kind: EventKind,
+    #[description = "id of event"]
+    id: usize,
+    #[description = "New start time, e.g. a Unix timestamp, `2024-05-03T20:00` or `next friday 20:00`"]
+    timestamp: Option<String>,
+    #[description = "Repeat every N"]
+    n: Option<i64>,
+    #[description = "type"]
+    t: Option<RepeatType>,
+    #[description = "Label shown with the event, `none` to remove it"]
+    label: Option<String>,
+    #[description = "Longer explanation of what the event is for, `none` to remove it"]
+    description: Option<String>,
+    #[description = "Whether the event fires, false to pause it"]
+    enabled: Option<bool>,
+) -> Result<(),Error> {
+    if timestamp.is_none() && n.is_none() && t.is_none() && label.is_none() && description.is_none() && enabled.is_none() {
+        ctx.say("Nothing to change").await?;
+        return Ok(());
+    }
+
+    let (file_path, file, mut data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), false).await?;
+
+    let Some(event) = data.event_mut(kind, id) else {
+        ctx.say(format!("No such {kind:?} event exists with id {id}")).await?;
+        file.unlock()?;
+        return Ok(());
+    };
+
+    if let Some(timestamp) = timestamp {
+        let Some(start) = parse_start(&ctx, &timestamp, event.timezone.as_deref()).await? else {
+            file.unlock()?;
+            return Ok(());
+        };
+        if let Recurrence::RRule{rrule, exdates} = &event.repeating {
+            if let Err(e) = build_rrule_set(rrule, start, &event.tz(), exdates) {
+                ctx.say(format!("Invalid RRULE: {e}")).await?;
+                file.unlock()?;
+                return Ok(());
+            }
+        }
+        event.initial = start;
+    }
+
+    if n.is_some() || t.is_some() {
+        let repeating = match &event.repeating {
+            Recurrence::Interval(interval) => interval_or_once(Some(n.unwrap_or(interval.n)), Some(t.unwrap_or(interval.t))),
+            Recurrence::Once => interval_or_once(n, t),
+            _ => Err("Only repeating intervals and one-off events have an interval to change".to_string()),
+        };
+        match repeating {
+            Ok(repeating) => event.repeating = repeating,
+            Err(e) => {
+                ctx.say(e).await?;
+                file.unlock()?;
+                return Ok(());
+            }
+        }
+    }
+
+    if let Some(label) = label {
+        event.label = clearable(&label);
+    }
+    if let Some(description) = description {
+        event.description = clearable(&description);
+    }
+    if let Some(enabled) = enabled {
+        event.enabled = enabled;
+    }
+
+    let describe = event.describe();
+    let title = event.title();
+    let start = event.initial;
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    ctx.say(format!("Updated {kind:?} event {title}, {describe} starting on <t:{start}:F>")).await?;
+    Ok(())
+}
+
+/// Treats an empty value or `none` as removing an optional text field.
+fn clearable(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() || value.eq_ignore_ascii_case("none") {
+        return None;
+    }
+    Some(value.to_string())
+}
+
+/// Warnings about an added event or the schedule as a whole, each on a new line, to follow a confirmation message.
+fn lint_suffix(data: &ClosedData, kind: EventKind, id: usize) -> String {
+    return lint_schedule(data, &SystemClock).into_iter()
+        .filter(|x| x.involves(kind, id) || x.is_general())
+        .map(|x| format!("\n:warning: {}", x.describe()))
+        .collect();
+}
+
+async fn set_enabled(ctx: &Context<'_>, kind: EventKind, id: usize, enabled: bool) -> Result<(), Error> {
+    let (file_path, file, mut data) = load_json::<ClosedData>(Some(ctx),"closed.json".to_string(), false).await?;
+
+    let Some(event) = data.event_mut(kind, id) else {
+        ctx.say(format!("No such {kind:?} event exists with id {id}")).await?;
+        file.unlock()?;
+        return Ok(());
+    };
+    if event.enabled == enabled {
+        ctx.say(format!("{kind:?} event {id} is already {}", if enabled {"running"} else {"paused"})).await?;
+        file.unlock()?;
+        return Ok(());
+    }
+    event.enabled = enabled;
+
+    write_tmp_and_copy(Some(ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    ctx.say(format!("{kind:?} event {id} {}", if enabled {"resumed"} else {"paused, it won't fire until resumed"})).await?;
+    Ok(())
+}
+
+/// Admin only command to stop an event from firing without removing it
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn pauseevent(
+    ctx: Context<'_>,
+    #[description = "Kind of event"]
+    kind: EventKind,
+    #[description = "id of event"]
+    id: usize,
+) -> Result<(),Error> {
+    set_enabled(&ctx, kind, id, false).await
+}
+
+/// Admin only command to let a paused event fire again
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn resumeevent(
+    ctx: Context<'_>,
+    #[description = "Kind of event"]
+    kind: EventKind,
+    #[description = "id of event"]
+    id: usize,
+) -> Result<(),Error> {
+    set_enabled(&ctx, kind, id, true).await
+}
+
+async fn parse_date(ctx: &Context<'_>, date: &str) -> Result<Option<NaiveDate>, Error> {
+    match NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d") {
+        Ok(val) => Ok(Some(val)),
+        Err(_) => {
+            ctx.say(format!("Invalid date `{date}`, expected YYYY-MM-DD")).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Admin only command to skip a single date for an event without removing it
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn skipdate(
+    ctx: Context<'_>,
+    #[description = "Kind of event"]
+    kind: EventKind,
+    #[description = "id of event"]
+    id: usize,
+    #[description = "Date to skip in the event's timezone, YYYY-MM-DD"]
+    date: String,
+) -> Result<(),Error> {
+    let Some(date) = parse_date(&ctx, &date).await? else {
+        return Ok(());
+    };
+
+    let (file_path, file, mut data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), false).await?;
+
+    let Some(event) = data.event_mut(kind, id) else {
+        ctx.say(format!("No such {kind:?} event exists with id {id}")).await?;
+        file.unlock()?;
+        return Ok(());
+    };
+    if event.skip_dates.contains(&date) {
+        ctx.say(format!("{kind:?} event {id} already skips {date}")).await?;
+        file.unlock()?;
+        return Ok(());
+    }
+    event.skip_dates.push(date);
+    event.skip_dates.sort();
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    ctx.say(format!("{kind:?} event {id} will be skipped on {date}")).await?;
+    Ok(())
+}
+
+/// Admin only command to stop skipping a date for an event
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn unskipdate(
+    ctx: Context<'_>,
+    #[description = "Kind of event"]
+    kind: EventKind,
+    #[description = "id of event"]
+    id: usize,
+    #[description = "Skipped date, YYYY-MM-DD"]
+    date: String,
+) -> Result<(),Error> {
+    let Some(date) = parse_date(&ctx, &date).await? else {
+        return Ok(());
+    };
+
+    let (file_path, file, mut data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), false).await?;
+
+    let Some(event) = data.event_mut(kind, id) else {
+        ctx.say(format!("No such {kind:?} event exists with id {id}")).await?;
+        file.unlock()?;
+        return Ok(());
+    };
+    let Some(index) = event.skip_dates.iter().position(|x| *x == date) else {
+        ctx.say(format!("{kind:?} event {id} doesn't skip {date}")).await?;
+        file.unlock()?;
+        return Ok(());
+    };
+    event.skip_dates.remove(index);
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    ctx.say(format!("{kind:?} event {id} will no longer be skipped on {date}")).await?;
+    Ok(())
+}
+
+/// Admin only command to add a holiday on which no scheduled events happen
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn addholiday(
+    ctx: Context<'_>,
+    #[rest]
+    #[description = "Date, YYYY-MM-DD"]
+    date: String,
+) -> Result<(),Error> {
+    let Some(date) = parse_date(&ctx, &date).await? else {
+        return Ok(());
+    };
+
+    let (file_path, file, mut data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), false).await?;
+
+    if data.holidays.contains(&date) {
+        ctx.say(format!("{date} is already a holiday")).await?;
+        file.unlock()?;
+        return Ok(());
+    }
+    data.holidays.push(date);
+    data.holidays.sort();
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    ctx.say(format!("Added holiday on {date}, no scheduled events will happen that day")).await?;
+    Ok(())
+}
+
+/// Admin only command to remove a holiday
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn removeholiday(
+    ctx: Context<'_>,
+    #[rest]
+    #[description = "Date, YYYY-MM-DD"]
+    date: String,
+) -> Result<(),Error> {
+    let Some(date) = parse_date(&ctx, &date).await? else {
+        return Ok(());
+    };
+
+    let (file_path, file, mut data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), false).await?;
+
+    let Some(index) = data.holidays.iter().position(|x| *x == date) else {
+        ctx.say(format!("{date} is not a holiday")).await?;
+        file.unlock()?;
+        return Ok(());
+    };
+    data.holidays.remove(index);
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    ctx.say(format!("Removed holiday on {date}")).await?;
+    Ok(())
+}
+
+/// Admin only command to list holidays on which no scheduled events happen
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn listholidays(
+    ctx: Context<'_>,
+) -> Result<(), Error> {
+    let (_file_path, _file, data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), true).await?;
+    _file.unlock()?;
+
+    if data.holidays.is_empty() {
+        ctx.say("No holidays are set").await?;
+        return Ok(());
+    }
+
+    let holidays = data.holidays.iter().map(|x| x.to_string()).collect::<Vec<String>>().join("\n");
+    ctx.say(format!("Holidays:\n{holidays}")).await?;
+    Ok(())
+}
\ No newline at end of file
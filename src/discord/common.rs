@@ -1,17 +1,123 @@
+use chrono_tz::Tz;
+use fs4::tokio::AsyncFileExt;
 use reqwest::StatusCode;
 use serde::{Serialize, Deserialize};
 
 use crate::commonio::*;
+use crate::repeat::parse_timezone;
 
 #[derive(Serialize,Deserialize,Debug)]
 pub struct GeneralData {
     pub channel_id: Option<u64>,
     pub admin_roles: Option<Vec<u64>>,
     pub info_api: Option<String>,
+    #[serde(default)]
+    pub announcements: Announcements,
+    #[serde(default)]
+    pub guild_events: GuildEvents,
+    /// IANA timezone admin input is read in and new events repeat in, UTC if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+}
+
+impl GeneralData {
+    pub fn tz(&self) -> Tz {
+        self.timezone.as_deref().and_then(|x| parse_timezone(x).ok()).unwrap_or(Tz::UTC)
+    }
+}
+
+/// The server's timezone from data.json, UTC if it hasn't been set.
+pub async fn load_timezone() -> Result<Tz, Error> {
+    let (_file_path, file, data) = load_json::<GeneralData>(None, "data.json".to_string(), true).await?;
+    file.unlock()?;
+    Ok(data.tz())
 }
 
 impl Default for GeneralData {
-    fn default() -> Self {GeneralData{channel_id: None, admin_roles: None, info_api: None}}
+    fn default() -> Self {GeneralData{channel_id: None, admin_roles: None, info_api: None, announcements: Announcements::default(), guild_events: GuildEvents::default(), timezone: None}}
+}
+
+/// Where and how the bot posts when the headless opens or closes.
+///
+/// Templates can use `{status}` (open or closed), `{action}` (opens or closes), `{time}`, `{relative}`, `{minutes}`, `{label}` and `{cause}`.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[serde(default)]
+pub struct Announcements {
+    pub channel_id: Option<u64>,
+    pub ping_role: Option<u64>,
+    pub close_template: String,
+    pub open_template: String,
+    pub warning_template: String,
+    /// Minutes before each change to post a warning.
+    pub warning_minutes: Vec<i64>,
+}
+
+impl Default for Announcements {
+    fn default() -> Self {
+        Announcements{
+            channel_id: None,
+            ping_role: None,
+            close_template: "The headless is now closed to members of the closed whitelist".to_string(),
+            open_template: "The headless is now open".to_string(),
+            warning_template: "The headless {action} in {minutes} minutes, at {time}".to_string(),
+            warning_minutes: Vec::new(),
+        }
+    }
+}
+
+/// Settings for mirroring closed sessions as Discord scheduled events, and the events created so far.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[serde(default)]
+pub struct GuildEvents {
+    /// Guild the events are created in, mirroring is off when not set.
+    pub guild_id: Option<u64>,
+    pub location: String,
+    /// How many days ahead to create events for.
+    pub days_ahead: i64,
+    /// Minutes before a session to DM users who are interested in its event.
+    pub reminder_minutes: Option<i64>,
+    pub mirrored: Vec<MirroredSession>,
+}
+
+impl Default for GuildEvents {
+    fn default() -> Self {
+        GuildEvents{guild_id: None, location: "Headless".to_string(), days_ahead: 14, reminder_minutes: None, mirrored: Vec::new()}
+    }
+}
+
+/// A closed session and the scheduled event created for it.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct MirroredSession {
+    pub event_id: u64,
+    pub start: i64,
+    pub end: i64,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub started: bool,
+    #[serde(default)]
+    pub reminded: bool,
+}
+
+/// Records when a whitelist entry expires, or that it's permanent, returning when it was set to expire before.
+pub async fn set_expiry(ctx: &Context<'_>, list: WhitelistName, uid: &str, expires_at: Option<i64>) -> Result<Option<i64>, Error> {
+    let (file_path, file, mut expiries) = load_json::<WhitelistExpiries>(Some(ctx), "expiries.json".to_string(), false).await?;
+
+    let previous = expiries.expires_at(list, uid);
+    if previous == expires_at {
+        file.unlock()?;
+        return Ok(previous);
+    }
+    expiries.set(list, uid, expires_at);
+
+    write_tmp_and_copy(Some(ctx), &file_path, file, &serde_json::to_string(&expiries)?).await?;
+    Ok(previous)
+}
+
+/// Reply text for when a temporary entry is removed, empty for permanent ones.
+pub fn expiry_suffix(expires_at: Option<i64>) -> String {
+    expires_at.map(|x| format!(", it will be removed <t:{x}:R> (<t:{x}:f>)")).unwrap_or_default()
 }
 
 pub async fn check_userid(ctx: &Context<'_>, uid: &str) -> Result<bool,Error>{
@@ -0,0 +1,335 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use fs4::tokio::AsyncFileExt;
+
+use poise::serenity_prelude as serenity;
+
+use super::checks::admin_check;
+use super::common::{GeneralData, GuildEvents, MirroredSession};
+use crate::clock::{Clock, SystemClock};
+use crate::commonio::*;
+use crate::scheduler::Scheduler;
+
+//Re-check at least this often, so sessions move into the window as time passes.
+const MAX_SYNC_SLEEP_SECONDS: i64 = 300;
+//Discord allows 100 scheduled events per guild, leave room for the server's own.
+const MAX_SESSIONS: usize = 20;
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_DESCRIPTION_LENGTH: usize = 1000;
+const MAX_INTERESTED_USERS: u64 = 100;
+
+/// A stretch of time the headless is closed, from a close to the following open.
+struct Session {
+    /// None for the session the headless is currently closed for.
+    start: Option<i64>,
+    end: i64,
+    name: String,
+    description: String,
+}
+
+/// Closed sessions starting before `until`, including the current one if the headless is closed now.
+fn upcoming_sessions(data: &ClosedData, clock: &impl Clock, until: i64) -> Vec<Session> {
+    let mut sessions: Vec<Session> = Vec::new();
+    //(start, cause) of the close waiting for its open.
+    let mut pending: Option<(Option<i64>, ModeCause)> = None;
+    if data.is_currently_closed(clock) {
+        pending = Some((None, data.mode_cause(clock)));
+    }
+
+    for transition in data.transitions(clock, MAX_SESSIONS * 2 + 1) {
+        if transition.closed {
+            if transition.at > until {
+                break;
+            }
+            pending = Some((Some(transition.at), transition.cause));
+        } else if let Some((start, cause)) = pending.take() {
+            sessions.push(Session{start, end: transition.at, name: session_name(data, &cause), description: session_description(data, &cause)});
+        }
+    }
+    //A close with no open after it isn't a session Discord can show, since events need an end.
+    sessions.truncate(MAX_SESSIONS);
+    return sessions;
+}
+
+fn session_name(data: &ClosedData, cause: &ModeCause) -> String {
+    let name = match data.cause_label(cause) {
+        Some(label) => format!("Headless closed: {label}"),
+        None => "Headless closed".to_string(),
+    };
+    return name.chars().take(MAX_NAME_LENGTH).collect();
+}
+
+fn session_description(data: &ClosedData, cause: &ModeCause) -> String {
+    let event = match cause {
+        ModeCause::CloseEvent{id} => data.close_events.get(id),
+        ModeCause::ClosedWindow{id} => data.closed_windows.get(id).map(|x| &x.start),
+        _ => None,
+    };
+    let mut description = "Only members of the closed whitelist can join the headless".to_string();
+    if let Some(text) = event.and_then(|x| x.description.as_deref()) {
+        description.push_str("\n\n");
+        description.push_str(text);
+    }
+    return description.chars().take(MAX_DESCRIPTION_LENGTH).collect();
+}
+
+fn timestamp(at: i64) -> Result<serenity::Timestamp, Error> {
+    Ok(serenity::Timestamp::from_unix_timestamp(at)?)
+}
+
+async fn create_event(http: &serenity::Http, guild: serenity::GuildId, settings: &GuildEvents, session: &Session, start: i64) -> Result<MirroredSession, Error> {
+    let (start_time, end_time) = (timestamp(start)?, timestamp(session.end)?);
+    let event = guild.create_scheduled_event(http, |e| {
+        e.name(&session.name)
+            .description(&session.description)
+            .kind(serenity::ScheduledEventType::External)
+            .location(&settings.location)
+            .start_time(start_time)
+            .end_time(end_time)
+    }).await?;
+    Ok(MirroredSession{event_id: event.id.0, start, end: session.end, name: session.name.clone(), description: session.description.clone(), started: false, reminded: false})
+}
+
+/// DMs everyone interested in a session's event that it's about to start.
+async fn send_reminders(http: &serenity::Http, guild: serenity::GuildId, mirrored: &MirroredSession) -> Result<(), Error> {
+    let users = guild.scheduled_event_users(http, mirrored.event_id, Some(MAX_INTERESTED_USERS)).await?;
+    let (name, start) = (&mirrored.name, mirrored.start);
+    for interested in users {
+        let sent = interested.user.direct_message(http, |m| m.content(format!("Reminder: **{name}** starts <t:{start}:R>, at <t:{start}:f>"))).await;
+        if let Err(e) = sent {
+            println!("reminder to {}:{e:?}", interested.user.id);
+        }
+    }
+    Ok(())
+}
+
+/// Creates, updates and deletes scheduled events to match the upcoming closed sessions, returning how many seconds until anything is next due.
+async fn sync_guild_events(http: &serenity::Http) -> Result<i64, Error> {
+    let (_file_path, file, general) = load_json::<GeneralData>(None, "data.json".to_string(), true).await?;
+    file.unlock()?;
+    let settings = general.guild_events;
+    let Some(guild_id) = settings.guild_id else {
+        return Ok(MAX_SYNC_SLEEP_SECONDS);
+    };
+    let guild = serenity::GuildId(guild_id);
+
+    let (_file_path, file, data) = load_json::<ClosedData>(None, "closed.json".to_string(), true).await?;
+    file.unlock()?;
+
+    let now = SystemClock.now();
+    let sessions = upcoming_sessions(&data, &SystemClock, now.saturating_add(settings.days_ahead * 86400));
+
+    let mut previous = settings.mirrored.clone();
+    let mut mirrored: Vec<MirroredSession> = Vec::new();
+    for session in &sessions {
+        let found = previous.iter().position(|x| match session.start {
+            Some(start) => x.start == start,
+            None => x.start <= now,
+        });
+        let Some(mut existing) = found.map(|x| previous.remove(x)) else {
+            //The current session can't be created once it has started, events can't start in the past.
+            if let Some(start) = session.start {
+                match create_event(http, guild, &settings, session, start).await {
+                    Ok(created) => mirrored.push(created),
+                    Err(e) => println!("create_scheduled_event:{e:?}"),
+                }
+            }
+            continue;
+        };
+
+        if existing.end != session.end || existing.name != session.name || existing.description != session.description {
+            let end_time = timestamp(session.end)?;
+            let edited = guild.edit_scheduled_event(http, existing.event_id, |e| {
+                e.name(&session.name).description(&session.description).end_time(end_time)
+            }).await;
+            match edited {
+                Ok(_) => {
+                    existing.end = session.end;
+                    existing.name = session.name.clone();
+                    existing.description = session.description.clone();
+                },
+                Err(e) => println!("edit_scheduled_event:{e:?}"),
+            }
+        }
+        if existing.start <= now && !existing.started {
+            let started = guild.edit_scheduled_event(http, existing.event_id, |e| e.status(serenity::ScheduledEventStatus::Active)).await;
+            match started {
+                Ok(_) => existing.started = true,
+                Err(e) => println!("start_scheduled_event:{e:?}"),
+            }
+        }
+        mirrored.push(existing);
+    }
+
+    //Sessions that have ended or were removed from the schedule.
+    for old in previous {
+        let result = if old.started {
+            guild.edit_scheduled_event(http, old.event_id, |e| e.status(serenity::ScheduledEventStatus::Completed)).await.map(|_| ())
+        } else {
+            guild.delete_scheduled_event(http, old.event_id).await
+        };
+        //Usually the event was already ended or deleted in Discord, so there's nothing to retry.
+        if let Err(e) = result {
+            println!("remove_scheduled_event:{e:?}");
+        }
+    }
+
+    if let Some(minutes) = settings.reminder_minutes {
+        for session in mirrored.iter_mut().filter(|x| !x.reminded && x.start - minutes * 60 <= now) {
+            session.reminded = true;
+            if session.start > now {
+                if let Err(e) = send_reminders(http, guild, session).await {
+                    println!("send_reminders:{e:?}");
+                }
+            }
+        }
+    }
+
+    let mut sleep_for = MAX_SYNC_SLEEP_SECONDS;
+    for session in &mirrored {
+        let reminder = settings.reminder_minutes.filter(|_| !session.reminded).map(|x| session.start - x * 60);
+        let due = [reminder, Some(session.start).filter(|_| !session.started), Some(session.end)];
+        for at in due.into_iter().flatten() {
+            sleep_for = sleep_for.min(at - now);
+        }
+    }
+
+    let (file_path, file, mut general) = load_json::<GeneralData>(None, "data.json".to_string(), false).await?;
+    //Turned off or moved to another guild while syncing, the command that did it cleans up.
+    if general.guild_events.guild_id != Some(guild_id) {
+        file.unlock()?;
+        return Ok(MAX_SYNC_SLEEP_SECONDS);
+    }
+    general.guild_events.mirrored = mirrored;
+    write_tmp_and_copy(None, &file_path, file, &serde_json::to_string(&general)?).await?;
+
+    Ok(sleep_for)
+}
+
+/// Keeps the guild's scheduled events in sync with the schedule, re-syncing whenever it's edited.
+pub async fn guild_events_loop(http: Arc<serenity::Http>, scheduler: Scheduler) {
+    let mut edits = scheduler.watch_edits();
+    loop {
+        let sleep_for = match sync_guild_events(&http).await {
+            Ok(sleep_for) => sleep_for,
+            Err(e) => {
+                println!("sync_guild_events:{e:?}");
+                MAX_SYNC_SLEEP_SECONDS
+            },
+        };
+
+        tokio::select! {
+            _ = edits.changed() => (),
+            _ = tokio::time::sleep(Duration::from_secs(sleep_for.max(1) as u64)) => (),
+        }
+    }
+}
+
+/// Admin only commands to show closed sessions in the server's Events tab
+#[poise::command(slash_command, subcommands("guildeventsenable", "guildeventsdisable", "guildeventsreminder"), check = "admin_check")]
+pub async fn guildevents(
+    _ctx: Context<'_>,
+) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Create scheduled events in this server for upcoming closed sessions
+#[poise::command(slash_command, rename = "enable", guild_only, check = "admin_check")]
+pub async fn guildeventsenable(
+    ctx: Context<'_>,
+    #[description = "Location shown on the events, defaults to `Headless`"]
+    location: Option<String>,
+    #[description = "How many days ahead to create events for, defaults to 14"]
+    days: Option<i64>,
+) -> Result<(), Error> {
+    let Some(guild_id) = ctx.guild_id() else {
+        ctx.say("This command has to be used in a server").await?;
+        return Ok(());
+    };
+    if days.is_some_and(|x| !(1..=90).contains(&x)) {
+        ctx.say("Days ahead must be between 1 and 90").await?;
+        return Ok(());
+    }
+
+    let (file_path, file, mut data) = load_json::<GeneralData>(Some(&ctx),"data.json".to_string(), false).await?;
+
+    let settings = &mut data.guild_events;
+    if settings.guild_id.is_some_and(|x| x != guild_id.0) {
+        //Events made in the old guild are left there, the mapping only covers one guild.
+        settings.mirrored.clear();
+    }
+    settings.guild_id = Some(guild_id.0);
+    if let Some(location) = location {
+        settings.location = location.trim().to_string();
+    }
+    if let Some(days) = days {
+        settings.days_ahead = days;
+    }
+    let (location, days_ahead) = (settings.location.clone(), settings.days_ahead);
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+    //Syncs straight away rather than at the next scheduled check.
+    ctx.data().scheduler.schedule_changed();
+
+    ctx.say(format!("Closed sessions in the next {days_ahead} days will be shown as events at `{location}`")).await?;
+    Ok(())
+}
+
+/// Stop creating scheduled events and delete the ones already created
+#[poise::command(slash_command, rename = "disable", check = "admin_check")]
+pub async fn guildeventsdisable(
+    ctx: Context<'_>,
+) -> Result<(), Error> {
+    let (file_path, file, mut data) = load_json::<GeneralData>(Some(&ctx),"data.json".to_string(), false).await?;
+
+    let Some(guild_id) = data.guild_events.guild_id.take() else {
+        file.unlock()?;
+        ctx.say("Scheduled events aren't turned on").await?;
+        return Ok(());
+    };
+    let mirrored = std::mem::take(&mut data.guild_events.mirrored);
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+
+    let mut failed = 0;
+    for session in &mirrored {
+        if let Err(e) = serenity::GuildId(guild_id).delete_scheduled_event(ctx, session.event_id).await {
+            println!("delete_scheduled_event:{e:?}");
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        ctx.say(format!("Stopped creating events, {failed} of {} events couldn't be deleted and may need removing by hand", mirrored.len())).await?;
+    } else {
+        ctx.say(format!("Stopped creating events and deleted {}", mirrored.len())).await?;
+    }
+    Ok(())
+}
+
+/// Set how many minutes before a session to DM users interested in its event
+#[poise::command(slash_command, rename = "reminder", check = "admin_check")]
+pub async fn guildeventsreminder(
+    ctx: Context<'_>,
+    #[description = "Minutes before, leave empty to stop sending reminders"]
+    minutes: Option<i64>,
+) -> Result<(), Error> {
+    if minutes.is_some_and(|x| x <= 0) {
+        ctx.say("Minutes must be more than 0").await?;
+        return Ok(());
+    }
+
+    let (file_path, file, mut data) = load_json::<GeneralData>(Some(&ctx),"data.json".to_string(), false).await?;
+
+    data.guild_events.reminder_minutes = minutes;
+
+    write_tmp_and_copy(Some(&ctx), &file_path, file, &serde_json::to_string(&data)?).await?;
+    ctx.data().scheduler.schedule_changed();
+
+    match minutes {
+        Some(minutes) => ctx.say(format!("Users interested in a session's event will be DMed {minutes} minutes before it starts")).await?,
+        None => ctx.say("Reminders turned off").await?,
+    };
+    Ok(())
+}
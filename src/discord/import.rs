@@ -0,0 +1,233 @@
+use std::time::Duration;
+
+use fs4::tokio::AsyncFileExt;
+
+use poise::serenity_prelude as serenity;
+
+use super::checks::admin_check;
+use super::closedwhitelist::new_event;
+use super::common::load_timezone;
+use crate::clock::{Clock, SystemClock};
+use crate::commonio::*;
+use crate::ics::{parse_ics, CalendarEvent};
+use crate::repeat::*;
+
+//Discord lets bots download much larger files, but no calendar worth importing is this big.
+const MAX_FILE_BYTES: u64 = 1024 * 1024;
+const CONFIRM_TIMEOUT_SECONDS: u64 = 120;
+//Keeps the preview short enough to read.
+const MAX_PREVIEW_LINES: usize = 15;
+//Discord's limits on the length of an embed description and of a message.
+const MAX_DESCRIPTION_CHARS: usize = 4096;
+const MAX_MESSAGE_CHARS: usize = 2000;
+
+#[derive(Debug, poise::ChoiceParameter, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    #[name = "Merge with existing events"]
+    Merge,
+    #[name = "Replace existing events"]
+    Replace,
+}
+
+/// Turns a calendar event into a closed window, which closes the headless when it starts and opens it again when it ends.
+fn to_window(event: CalendarEvent, owner: &str) -> Result<ClosedWindow, String> {
+    let repeating = match event.rrule {
+        Some(rrule) => {
+            let tz = event.timezone.as_deref().map(parse_timezone).transpose()?.unwrap_or(chrono_tz::UTC);
+            build_rrule_set(&rrule, event.start, &tz, &event.exdates)?;
+            Recurrence::RRule{rrule, exdates: event.exdates}
+        },
+        None => Recurrence::Once,
+    };
+    let start = new_event(owner, event.start, repeating, event.timezone, event.summary, event.description);
+    Ok(ClosedWindow{id: 0, start, duration: event.duration})
+}
+
+/// Whether two windows close at the same times, so importing the same calendar twice doesn't add everything again.
+fn same_window(a: &ClosedWindow, b: &ClosedWindow) -> bool {
+    a.start.initial == b.start.initial && a.duration == b.duration && a.start.describe() == b.start.describe()
+}
+
+fn window_line(window: &ClosedWindow, now: i64, holidays: &[chrono::NaiveDate]) -> String {
+    let label = window.start.label.as_deref().unwrap_or("Untitled");
+    let next = window.next_start(now, holidays);
+    let next_s = if next == i64::MAX {String::new()} else {format!(", next <t:{next}:f>")};
+    format!("**{label}** {}{next_s}", window.describe())
+}
+
+/// Admin only command to add closed windows from an .ics calendar file, after previewing them
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn importcalendar(
+    ctx: Context<'_>,
+    #[description = "Calendar exported as an .ics file"]
+    file: serenity::Attachment,
+    #[description = "Keep existing events, or archive them and use only the calendar"]
+    mode: ImportMode,
+    #[description = "IANA timezone for times the calendar doesn't give one for, defaults to the server's timezone"]
+    timezone: Option<String>,
+) -> Result<(), Error> {
+    let default_tz = match timezone.as_deref().map(parse_timezone).transpose() {
+        Ok(Some(tz)) => tz,
+        Ok(None) => load_timezone().await?,
+        Err(e) => {
+            ctx.say(e).await?;
+            return Ok(());
+        }
+    };
+    if file.size > MAX_FILE_BYTES {
+        ctx.say("The file is too large, calendars can be at most 1 MB").await?;
+        return Ok(());
+    }
+    ctx.defer().await?;
+
+    let contents = String::from_utf8_lossy(&file.download().await?).to_string();
+    let (events, mut skipped) = match parse_ics(&contents, &default_tz) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            ctx.say(e).await?;
+            return Ok(());
+        }
+    };
+
+    let (_file_path, closed_file, data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), true).await?;
+    closed_file.unlock()?;
+
+    let now = SystemClock.now();
+    let owner = ctx.author().id.to_string();
+    let mut windows: Vec<ClosedWindow> = Vec::new();
+    let (mut finished, mut duplicates) = (0, 0);
+    for event in events {
+        let name = event.summary.clone().or(event.uid.clone()).unwrap_or("an untitled event".to_string());
+        let window = match to_window(event, &owner) {
+            Ok(window) => window,
+            Err(e) => {
+                skipped.push(format!("{name}: {e}"));
+                continue;
+            }
+        };
+        if window.is_finished(now) {
+            finished += 1;
+        } else if mode == ImportMode::Merge && data.closed_windows.values().any(|x| same_window(x, &window)) {
+            duplicates += 1;
+        } else {
+            windows.push(window);
+        }
+    }
+    windows.sort_by_key(|x| x.next_start(now, &data.holidays));
+
+    if windows.is_empty() {
+        let mut lines = vec![format!("Nothing to import, {finished} events are in the past and {duplicates} were already imported")];
+        lines.extend(skipped_lines(&skipped));
+        ctx.say(fit_lines(&lines, MAX_MESSAGE_CHARS)).await?;
+        return Ok(());
+    }
+
+    let mut preview: Vec<String> = windows.iter().take(MAX_PREVIEW_LINES).map(|x| window_line(x, now, &data.holidays)).collect();
+    if windows.len() > MAX_PREVIEW_LINES {
+        preview.push(format!("...and {} more", windows.len() - MAX_PREVIEW_LINES));
+    }
+    let existing = data.close_events.len() + data.open_events.len() + data.closed_windows.len();
+    let mode_s = match mode {
+        ImportMode::Merge => "They will be added alongside the existing events".to_string(),
+        ImportMode::Replace => format!("The {existing} existing events and windows will be archived and replaced"),
+    };
+    let mut notes: Vec<String> = vec![mode_s];
+    if finished > 0 {
+        notes.push(format!("{finished} events that are over were left out"));
+    }
+    if duplicates > 0 {
+        notes.push(format!("{duplicates} events that were already imported were left out"));
+    }
+
+    let mut lines = preview;
+    lines.push(String::new());
+    lines.extend(notes);
+    lines.extend(skipped_lines(&skipped));
+    let description = fit_lines(&lines, MAX_DESCRIPTION_CHARS);
+
+    let confirm_id = format!("{}confirm", ctx.id());
+    let cancel_id = format!("{}cancel", ctx.id());
+    let reply = ctx.send(|b| b.embed(|embed| {
+        embed.color(serenity::colours::branding::BLURPLE);
+        embed.title(format!("Import {} closed windows from {}?", windows.len(), file.filename));
+        embed.description(&description);
+        embed
+    }).components(|c| c.create_action_row(|row| {
+        row.create_button(|button| button.custom_id(&confirm_id).label("Import").style(serenity::ButtonStyle::Success));
+        row.create_button(|button| button.custom_id(&cancel_id).label("Cancel").style(serenity::ButtonStyle::Secondary))
+    }))).await?;
+
+    let filter_ids = (confirm_id.clone(), cancel_id.clone());
+    let interaction = serenity::CollectComponentInteraction::new(ctx)
+        .author_id(ctx.author().id)
+        .channel_id(ctx.channel_id())
+        .timeout(Duration::from_secs(CONFIRM_TIMEOUT_SECONDS))
+        .filter(move |x| x.data.custom_id == filter_ids.0 || x.data.custom_id == filter_ids.1)
+        .await;
+
+    let Some(interaction) = interaction else {
+        reply.edit(ctx, |b| b.content("Import timed out, nothing was changed").components(|c| c)).await?;
+        return Ok(());
+    };
+    interaction.create_interaction_response(ctx, |r| r.kind(serenity::InteractionResponseType::DeferredUpdateMessage)).await?;
+    if interaction.data.custom_id != confirm_id {
+        reply.edit(ctx, |b| b.content("Import cancelled, nothing was changed").components(|c| c)).await?;
+        return Ok(());
+    }
+
+    //Re-read the schedule in case it was edited while waiting for confirmation.
+    let (file_path, closed_file, mut data) = load_json::<ClosedData>(Some(&ctx),"closed.json".to_string(), false).await?;
+
+    let mut archived = 0;
+    if mode == ImportMode::Replace {
+        let now = SystemClock.now();
+        let events = data.close_events.drain().map(|(_, x)| (EventKind::Close, x, None))
+            .chain(data.open_events.drain().map(|(_, x)| (EventKind::Open, x, None)))
+            .chain(data.closed_windows.drain().map(|(_, x)| (EventKind::Window, x.start, Some(x.duration))))
+            .collect::<Vec<_>>();
+        archived = events.len();
+        data.archived_events.extend(events.into_iter().map(|(kind, event, duration)| ArchivedEvent{kind, archived_at: now, event, duration}));
+    }
+
+    let mut next_id = data.next_id(EventKind::Window);
+    let (first_id, count) = (next_id, windows.len());
+    for mut window in windows {
+        window.id = next_id;
+        window.start.id = next_id;
+        data.closed_windows.insert(next_id, window);
+        next_id += 1;
+    }
+
+    write_tmp_and_copy(Some(&ctx), &file_path, closed_file, &serde_json::to_string(&data)?).await?;
+
+    let archived_s = if mode == ImportMode::Replace {format!(", archived {archived} existing events")} else {String::new()};
+    reply.edit(ctx, |b| b.content(format!("Imported {count} closed windows with ids {first_id} to {}{archived_s}", next_id - 1)).components(|c| c)).await?;
+    Ok(())
+}
+
+fn skipped_lines(skipped: &[String]) -> Vec<String> {
+    let mut lines: Vec<String> = skipped.iter().take(MAX_PREVIEW_LINES).map(|x| format!(":warning: Left out {x}")).collect();
+    if skipped.len() > MAX_PREVIEW_LINES {
+        lines.push(format!("...and {} more left out", skipped.len() - MAX_PREVIEW_LINES));
+    }
+    return lines;
+}
+
+/// Joins lines up to `limit` characters, replacing the ones that don't fit with a line saying how many were cut.
+fn fit_lines(lines: &[String], limit: usize) -> String {
+    //Room for the line saying how many were cut.
+    let budget = limit - 32;
+    let mut text = String::new();
+    let mut used = 0;
+    for (i, line) in lines.iter().enumerate() {
+        let length = line.chars().count() + 1;
+        if used + length > budget {
+            text.push_str(&format!("...and {} more lines", lines.len() - i));
+            return text;
+        }
+        used += length;
+        text.push_str(line);
+        text.push('\n');
+    }
+    return text.trim_end().to_string();
+}
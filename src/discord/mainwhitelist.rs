@@ -1,25 +1,47 @@
+use chrono::Utc;
 use fs4::tokio::AsyncFileExt;
 
 use tokio::io::{BufReader, AsyncBufReadExt};
 
 use crate::commonio::*;
 use super::checks::*;
-use super::common::check_userid;
+use super::common::{check_userid, expiry_suffix, load_timezone, set_expiry};
+use crate::timeparse::parse_expiry;
 
 use poise::serenity_prelude as serenity;
 
+//Keeps the list of temporary entries within an embed's description limit.
+const MAX_LISTED: usize = 40;
+
 /// Admin only command to add users to the admin whitelist
 #[poise::command(slash_command, check = "admin_check")]
 pub async fn adduser(
     ctx: Context<'_>,
-    #[rest]
     #[description = "Resonite UserID"]
     uid: String,
+    #[rename = "for"]
+    #[description = "Remove them again after this long, e.g. `4h` or `1d`, leave empty to keep them"]
+    duration: Option<String>,
+    #[description = "Remove them again at this time, e.g. `tomorrow 2am` in the server's timezone"]
+    until: Option<String>,
 ) -> Result<(),Error> {
+    let tz = load_timezone().await?;
+    let expires_at = match parse_expiry(duration.as_deref(), until.as_deref(), &tz, Utc::now().timestamp()) {
+        Ok(expires_at) => expires_at,
+        Err(e) => {
+            ctx.say(e).await?;
+            return Ok(());
+        }
+    };
     if !check_userid(&ctx, &uid).await? {
         return Ok(());
     }
 
+    //Lock the expiries and revisions before the list, in the same order as purging expired entries does.
+    let (expiries_path, expiries_file, mut expiries) = load_json::<WhitelistExpiries>(Some(&ctx), "expiries.json".to_string(), false).await?;
+    let revisions = lock_revisions(Some(&ctx)).await?;
+    let previous = expiries.expires_at(WhitelistName::Admin, &uid);
+
     let dir = get_dir()?;
     let file_path = dir.join("usersadmin.txt");
 
@@ -36,19 +58,34 @@ pub async fn adduser(
     }
 
     if lines.contains(&uid) {
-        ctx.say("User is already in admin whitelist").await?;
         file.unlock()?;
+        revisions.release()?;
+        if expires_at.is_some() && previous.is_none() {
+            expiries_file.unlock()?;
+            ctx.say("User is already permanently in admin whitelist, remove them first to add them temporarily").await?;
+            return Ok(());
+        }
+        expiries.set(WhitelistName::Admin, &uid, expires_at);
+        write_tmp_and_copy(Some(&ctx), &expiries_path, expiries_file, &serde_json::to_string(&expiries)?).await?;
+        match (expires_at, previous) {
+            (Some(_), _) => ctx.say(format!("User is already in admin whitelist{}", expiry_suffix(expires_at))).await?,
+            (None, Some(_)) => ctx.say("User is already in admin whitelist, it will now be kept").await?,
+            (None, None) => ctx.say("User is already in admin whitelist").await?,
+        };
         return Ok(());
     }
 
     lines.push(uid.clone());
 
-    
+    //Write the expiry first, so a temporary entry is never in the list without it.
+    expiries.set(WhitelistName::Admin, &uid, expires_at);
+    write_tmp_and_copy(Some(&ctx), &expiries_path, expiries_file, &serde_json::to_string(&expiries)?).await?;
 
     let new_contents = lines.join("\n");
-    match write_tmp_and_copy(&ctx, &file_path, file, &new_contents).await {
+    match write_tmp_and_copy(Some(&ctx), &file_path, file, &new_contents).await {
         Ok(_) => {
-            ctx.say(format!("Successfully added record for {uid}!")).await?;
+            revisions.record(Some(&ctx), vec![Change::Added{list: WhitelistName::Admin, uid: uid.clone()}]).await?;
+            ctx.say(format!("Successfully added record for {uid}{}!", expiry_suffix(expires_at))).await?;
             return Ok(());
         }
         Err(e) => {
@@ -66,6 +103,7 @@ pub async fn removeuser(
     #[description = "Resonite UserID"]
     uid: String,
 ) -> Result<(),Error> {
+    let revisions = lock_revisions(Some(&ctx)).await?;
     let dir = get_dir()?;
     let file_path = dir.join("usersadmin.txt");
 
@@ -82,6 +120,7 @@ pub async fn removeuser(
     }
 
     if !lines.contains(&uid) {
+        revisions.release()?;
         ctx.say("UserID was not in admin whitelist").await?;
         return Ok(());
     }
@@ -90,8 +129,10 @@ pub async fn removeuser(
     lines.remove(index);
 
     let new_contents = lines.join("\n");
-    match write_tmp_and_copy(&ctx, &file_path, file, &new_contents).await {
+    match write_tmp_and_copy(Some(&ctx), &file_path, file, &new_contents).await {
         Ok(_) => {
+            revisions.record(Some(&ctx), vec![Change::Removed{list: WhitelistName::Admin, uid: uid.clone()}]).await?;
+            set_expiry(&ctx, WhitelistName::Admin, &uid, None).await?;
             ctx.say(format!("Successfully added record for {uid}!")).await?;
             return Ok(());
         }
@@ -114,6 +155,7 @@ pub async fn register(
         return Ok(());
     }
 
+    let revisions = lock_revisions(Some(&ctx)).await?;
     let dir = get_dir()?;
     let file_path = dir.join("usersauth.txt");
 
@@ -131,9 +173,16 @@ pub async fn register(
     }
 
     let mut existing_record = false;
+    let mut changes: Vec<Change> = Vec::new();
     for line_i in &mut lines {
         if line_i.starts_with(&userid){
             existing_record = true;
+            if let Some(old_uid) = line_i.split('=').nth(1) {
+                if old_uid != uid {
+                    changes.push(Change::Removed{list: WhitelistName::Auth, uid: old_uid.to_string()});
+                    changes.push(Change::Added{list: WhitelistName::Auth, uid: uid.clone()});
+                }
+            }
             *line_i = format!("{userid}={uid}");
         }
     }
@@ -141,12 +190,14 @@ pub async fn register(
     let mut operation = "changed";
     if !existing_record {
         operation = "created";
+        changes.push(Change::Added{list: WhitelistName::Auth, uid: uid.clone()});
         lines.push(format!("{userid}={uid}"))
     }
 
     let new_contents = lines.join("\n");
-    match write_tmp_and_copy(&ctx, &file_path, file, &new_contents).await {
+    match write_tmp_and_copy(Some(&ctx), &file_path, file, &new_contents).await {
         Ok(_) => {
+            revisions.record(Some(&ctx), changes).await?;
             ctx.say(format!("Successfully {operation} your record!")).await?;
             return Ok(());
         }
@@ -199,4 +250,33 @@ pub async fn checkregistered(
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+/// Admin only command to list temporary whitelist entries and when they'll be removed
+#[poise::command(slash_command, check = "admin_check")]
+pub async fn listtemporary(
+    ctx: Context<'_>,
+) -> Result<(), Error> {
+    let mut entries = load_expiries().await?.entries;
+    entries.sort_by_key(|x| x.expires_at);
+
+    let mut lines: Vec<String> = entries.iter().take(MAX_LISTED).map(|x| {
+        let list = match x.list {
+            WhitelistName::Admin => "admin",
+            WhitelistName::Auth => "registered",
+            WhitelistName::Closed => "closed",
+        };
+        let at = x.expires_at;
+        format!("`{}` in the {list} whitelist, removed <t:{at}:R> (<t:{at}:f>)", x.uid)
+    }).collect();
+    if entries.len() > MAX_LISTED {
+        lines.push(format!("...and {} more", entries.len() - MAX_LISTED));
+    }
+
+    ctx.send(|b| b.embed(|embed| {
+        embed.color(serenity::colours::branding::BLURPLE);
+        embed.title("Temporary whitelist entries");
+        embed.description(if lines.is_empty() {"No temporary entries".to_string()} else {lines.join("\n")});
+        embed
+    })).await?;
+    Ok(())
+}
@@ -0,0 +1,388 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+
+use crate::repeat::{parse_timezone, resolve_local};
+
+//Calendars exported with years of history can be large, but nobody closes the headless this often.
+const MAX_EVENTS: usize = 500;
+
+/// A VEVENT from an iCalendar file, with times resolved to timestamps.
+#[derive(Debug, Clone)]
+pub struct CalendarEvent {
+    pub uid: Option<String>,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    pub start: i64,
+    /// Length in seconds, from DTEND or DURATION.
+    pub duration: i64,
+    /// IANA timezone the start was given in, which repeats are computed in.
+    pub timezone: Option<String>,
+    pub rrule: Option<String>,
+    pub exdates: Vec<i64>,
+}
+
+/// A parsed content line, like `DTSTART;TZID=Europe/Berlin:20240503T200000`.
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(x, _)| x == name).map(|(_, x)| x.as_str())
+    }
+}
+
+/// Joins folded lines, which continue on the next line after a leading space or tab.
+fn unfold(input: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in input.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    return lines;
+}
+
+fn parse_property(line: &str) -> Option<Property> {
+    //Parameter values can be quoted and contain `;` or `:`, so split outside of quotes only.
+    let mut in_quotes = false;
+    let mut parts: Vec<String> = vec![String::new()];
+    let mut value: Option<String> = None;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => parts.push(String::new()),
+            ':' if !in_quotes => {
+                value = Some(line[i + 1..].to_string());
+                break;
+            },
+            _ => parts.last_mut()?.push(c),
+        }
+    }
+
+    let name = parts.first()?.trim().to_uppercase();
+    let params = parts[1..].iter()
+        .filter_map(|x| x.split_once('='))
+        .map(|(k, v)| (k.trim().to_uppercase(), v.to_string()))
+        .collect();
+    Some(Property{name, params, value: value?})
+}
+
+fn unescape(value: &str) -> String {
+    let mut result = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => (),
+        }
+    }
+    return result;
+}
+
+/// The timezone a time property is in, from its TZID, or the default for floating times.
+fn property_tz(property: &Property, default_tz: &Tz) -> Result<Tz, String> {
+    match property.param("TZID") {
+        //Some calendars prefix globally unique TZIDs with a slash.
+        Some(tzid) => parse_timezone(tzid.trim_start_matches('/')),
+        None => Ok(*default_tz),
+    }
+}
+
+/// Parses a DATE or DATE-TIME value, where dates are midnight and times without `Z` are local to `tz`.
+fn parse_value_time(value: &str, tz: &Tz) -> Result<i64, String> {
+    let value = value.trim();
+    if let Some(utc) = value.strip_suffix('Z') {
+        return NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S")
+            .map(|x| x.and_utc().timestamp())
+            .map_err(|_| format!("Invalid time `{value}`"));
+    }
+    if let Ok(local) = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") {
+        return Ok(resolve_local(tz, local));
+    }
+    match NaiveDate::parse_from_str(value, "%Y%m%d") {
+        Ok(date) => Ok(resolve_local(tz, date.and_hms_opt(0, 0, 0).unwrap())),
+        Err(_) => Err(format!("Invalid time `{value}`")),
+    }
+}
+
+fn is_date(property: &Property) -> bool {
+    property.param("VALUE").is_some_and(|x| x.eq_ignore_ascii_case("DATE")) || property.value.trim().len() == 8
+}
+
+/// Parses an RFC 5545 duration like `PT1H30M`, `P1D` or `P2W` into seconds.
+fn parse_duration(value: &str) -> Result<i64, String> {
+    let invalid = || format!("Invalid duration `{value}`");
+    let trimmed = value.trim();
+    let (sign, rest) = match trimmed.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+    };
+    let rest = rest.strip_prefix('P').ok_or_else(invalid)?;
+
+    let mut total: i64 = 0;
+    let mut amount = String::new();
+    let mut in_time = false;
+    for c in rest.chars() {
+        if c.is_ascii_digit() {
+            amount.push(c);
+            continue;
+        }
+        let unit_seconds = match (c, in_time) {
+            ('T', false) if amount.is_empty() => {
+                in_time = true;
+                continue;
+            },
+            ('W', false) => 604800,
+            ('D', false) => 86400,
+            ('H', true) => 3600,
+            ('M', true) => 60,
+            ('S', true) => 1,
+            _ => return Err(invalid()),
+        };
+        let seconds = amount.parse::<i64>().ok().and_then(|x| x.checked_mul(unit_seconds)).ok_or_else(invalid)?;
+        total = total.checked_add(seconds).ok_or_else(invalid)?;
+        amount.clear();
+    }
+    if !amount.is_empty() {
+        return Err(invalid());
+    }
+    Ok(sign * total)
+}
+
+/// The properties of one VEVENT block, before they are turned into a `CalendarEvent`.
+#[derive(Default)]
+struct RawEvent {
+    properties: Vec<Property>,
+}
+
+impl RawEvent {
+    fn get(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|x| x.name == name)
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        self.get(name).map(|x| unescape(&x.value)).filter(|x| !x.trim().is_empty())
+    }
+
+    /// Name used for the event in messages about it.
+    fn name(&self) -> String {
+        self.text("SUMMARY").or(self.text("UID")).unwrap_or("an untitled event".to_string())
+    }
+
+    fn convert(&self, default_tz: &Tz) -> Result<CalendarEvent, String> {
+        let dtstart = self.get("DTSTART").ok_or("it has no start time")?;
+        let tz = property_tz(dtstart, default_tz)?;
+        let start = parse_value_time(&dtstart.value, &tz)?;
+
+        let duration = match (self.get("DTEND"), self.get("DURATION")) {
+            (Some(dtend), _) => parse_value_time(&dtend.value, &property_tz(dtend, default_tz)?)? - start,
+            (None, Some(duration)) => parse_duration(&duration.value)?,
+            //All day events without an end last the day, anything else is a moment with nothing to close for.
+            (None, None) if is_date(dtstart) => 86400,
+            (None, None) => 0,
+        };
+        if duration <= 0 {
+            return Err("it doesn't last any time".to_string());
+        }
+
+        let mut exdates: Vec<i64> = Vec::new();
+        for exdate in self.properties.iter().filter(|x| x.name == "EXDATE") {
+            let exdate_tz = property_tz(exdate, default_tz)?;
+            for value in exdate.value.split(',').filter(|x| !x.trim().is_empty()) {
+                exdates.push(parse_value_time(value, &exdate_tz)?);
+            }
+        }
+
+        let timezone = (tz != Tz::UTC).then(|| tz.name().to_string());
+        Ok(CalendarEvent{
+            uid: self.text("UID"),
+            summary: self.text("SUMMARY"),
+            description: self.text("DESCRIPTION"),
+            start,
+            duration,
+            timezone,
+            rrule: self.get("RRULE").map(|x| x.value.trim().to_string()),
+            exdates,
+        })
+    }
+}
+
+/// Reads the VEVENTs from an iCalendar file, returning the events and a reason for each one that was left out.
+///
+/// Floating times are read in `default_tz`. Moved or cancelled occurrences of a repeating event (a VEVENT with a RECURRENCE-ID)
+/// are excluded from the repeating event, and moved ones are returned as events of their own.
+pub fn parse_ics(input: &str, default_tz: &Tz) -> Result<(Vec<CalendarEvent>, Vec<String>), String> {
+    let lines = unfold(input);
+    if !lines.iter().any(|x| x.trim().eq_ignore_ascii_case("BEGIN:VCALENDAR")) {
+        return Err("This doesn't look like an iCalendar (.ics) file".to_string());
+    }
+
+    //Only top level VEVENTs, alarms and other components inside them are ignored.
+    let mut raw_events: Vec<RawEvent> = Vec::new();
+    let mut current: Option<RawEvent> = None;
+    let mut depth = 0;
+    for line in &lines {
+        let Some(property) = parse_property(line) else {
+            continue;
+        };
+        match (property.name.as_str(), property.value.trim().to_uppercase().as_str()) {
+            ("BEGIN", "VEVENT") if current.is_none() => current = Some(RawEvent::default()),
+            ("BEGIN", _) if current.is_some() => depth += 1,
+            ("END", "VEVENT") if depth == 0 => raw_events.extend(current.take()),
+            ("END", _) if current.is_some() => depth -= 1,
+            _ if depth == 0 => {
+                if let Some(event) = current.as_mut() {
+                    event.properties.push(property);
+                }
+            },
+            _ => (),
+        }
+    }
+    if raw_events.len() > MAX_EVENTS {
+        return Err(format!("The calendar has {} events, at most {MAX_EVENTS} can be imported", raw_events.len()));
+    }
+
+    let mut skipped: Vec<String> = Vec::new();
+    let mut events: Vec<CalendarEvent> = Vec::new();
+    //(uid, replaced occurrence) for each moved or cancelled occurrence.
+    let mut overrides: Vec<(String, i64)> = Vec::new();
+    for raw in &raw_events {
+        let cancelled = raw.get("STATUS").is_some_and(|x| x.value.trim().eq_ignore_ascii_case("CANCELLED"));
+        if let (Some(recurrence_id), Some(uid)) = (raw.get("RECURRENCE-ID"), raw.text("UID")) {
+            match property_tz(recurrence_id, default_tz).and_then(|tz| parse_value_time(&recurrence_id.value, &tz)) {
+                Ok(at) => overrides.push((uid, at)),
+                Err(e) => skipped.push(format!("A changed occurrence of {}: {e}", raw.name())),
+            }
+        }
+        if cancelled {
+            continue;
+        }
+        match raw.convert(default_tz) {
+            Ok(event) => events.push(event),
+            Err(e) => skipped.push(format!("{}: {e}", raw.name())),
+        }
+    }
+
+    for (uid, at) in overrides {
+        let repeating = events.iter_mut().find(|x| x.rrule.is_some() && x.uid.as_deref() == Some(uid.as_str()));
+        if let Some(event) = repeating {
+            event.exdates.push(at);
+        }
+    }
+    Ok((events, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BERLIN: Tz = chrono_tz::Europe::Berlin;
+
+    fn calendar(events: &str) -> String {
+        format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{events}END:VCALENDAR\r\n")
+    }
+
+    #[test]
+    fn unfolds_continuation_lines() {
+        let lines = unfold("SUMMARY:Members\r\n  night\r\n\t out\r\nUID:1\r\n");
+        assert_eq!(lines, vec!["SUMMARY:Members night out".to_string(), "UID:1".to_string()]);
+    }
+
+    #[test]
+    fn parses_params() {
+        let property = parse_property("ATTENDEE;CN=\"Doe; John\";role=REQ-PARTICIPANT:mailto:john@example.com").unwrap();
+        assert_eq!(property.name, "ATTENDEE");
+        assert_eq!(property.param("CN"), Some("Doe; John"));
+        assert_eq!(property.param("ROLE"), Some("REQ-PARTICIPANT"));
+        assert_eq!(property.value, "mailto:john@example.com");
+        assert!(parse_property("no value here").is_none());
+    }
+
+    #[test]
+    fn unescapes_text() {
+        assert_eq!(unescape(r"Members\, friends\; guests\nBring snacks\\"), "Members, friends; guests\nBring snacks\\");
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("PT1H30M"), Ok(5400));
+        assert_eq!(parse_duration("P1DT1H"), Ok(90000));
+        assert_eq!(parse_duration("P2W"), Ok(1209600));
+        assert_eq!(parse_duration("-PT15M"), Ok(-900));
+        assert!(parse_duration("PT1D").is_err());
+        assert!(parse_duration("1H").is_err());
+        assert!(parse_duration("P99999999999999W").is_err());
+        assert!(parse_duration("P100000000000000DT9223372036854775807S").is_err());
+    }
+
+    #[test]
+    fn reads_times_in_their_timezones() {
+        let input = calendar(concat!(
+            "BEGIN:VEVENT\r\nUID:tzid\r\nDTSTART;TZID=\"/Europe/Berlin\":20300503T200000\r\nDTEND;TZID=Europe/Berlin:20300503T230000\r\nEND:VEVENT\r\n",
+            "BEGIN:VEVENT\r\nUID:utc\r\nDTSTART:20300601T120000Z\r\nDURATION:P1DT1H\r\nEND:VEVENT\r\n",
+            "BEGIN:VEVENT\r\nUID:allday\r\nDTSTART;VALUE=DATE:20301225\r\nEND:VEVENT\r\n",
+        ));
+        let (events, skipped) = parse_ics(&input, &BERLIN).unwrap();
+        assert!(skipped.is_empty());
+        let times: Vec<(i64, i64)> = events.iter().map(|x| (x.start, x.duration)).collect();
+        assert_eq!(times, vec![(1904061600, 10800), (1906545600, 90000), (1924383600, 86400)]);
+        assert_eq!(events[0].timezone.as_deref(), Some("Europe/Berlin"));
+    }
+
+    #[test]
+    fn reads_text_and_ignores_alarms() {
+        let input = calendar(concat!(
+            "BEGIN:VEVENT\r\nUID:weekly\r\nSUMMARY:Members night\\, weekly\r\nDESCRIPTION:Closed for members.\\nBring\r\n  friends\r\n",
+            "DTSTART:20300601T120000Z\r\nDURATION:PT2H\r\n",
+            "BEGIN:VALARM\r\nTRIGGER:-PT15M\r\nDESCRIPTION:Reminder\r\nEND:VALARM\r\nEND:VEVENT\r\n",
+        ));
+        let (events, _skipped) = parse_ics(&input, &BERLIN).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].summary.as_deref(), Some("Members night, weekly"));
+        assert_eq!(events[0].description.as_deref(), Some("Closed for members.\nBring friends"));
+    }
+
+    #[test]
+    fn excludes_exdates_and_changed_occurrences() {
+        let input = calendar(concat!(
+            "BEGIN:VEVENT\r\nUID:weekly\r\nDTSTART;TZID=Europe/Berlin:20300503T200000\r\nDURATION:PT3H\r\nRRULE:FREQ=WEEKLY;COUNT=6\r\n",
+            "EXDATE;TZID=Europe/Berlin:20300510T200000,20300524T200000\r\nEND:VEVENT\r\n",
+            //Moved to the next day.
+            "BEGIN:VEVENT\r\nUID:weekly\r\nRECURRENCE-ID;TZID=Europe/Berlin:20300517T200000\r\nSUMMARY:Moved\r\n",
+            "DTSTART;TZID=Europe/Berlin:20300518T210000\r\nDURATION:PT2H\r\nEND:VEVENT\r\n",
+            //Cancelled, so only excluded.
+            "BEGIN:VEVENT\r\nUID:weekly\r\nRECURRENCE-ID;TZID=Europe/Berlin:20300531T200000\r\nSTATUS:CANCELLED\r\n",
+            "DTSTART;TZID=Europe/Berlin:20300531T200000\r\nDURATION:PT3H\r\nEND:VEVENT\r\n",
+        ));
+        let (events, skipped) = parse_ics(&input, &BERLIN).unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].rrule.as_deref(), Some("FREQ=WEEKLY;COUNT=6"));
+        assert_eq!(events[0].exdates, vec![1904666400, 1905876000, 1905271200, 1906480800]);
+        assert_eq!(events[1].summary.as_deref(), Some("Moved"));
+        assert_eq!((events[1].start, events[1].duration), (1905361200, 7200));
+    }
+
+    #[test]
+    fn skips_events_it_cannot_use() {
+        let input = calendar(concat!(
+            "BEGIN:VEVENT\r\nSUMMARY:No start\r\nDURATION:PT1H\r\nEND:VEVENT\r\n",
+            "BEGIN:VEVENT\r\nSUMMARY:Windows zone\r\nDTSTART;TZID=W. Europe Standard Time:20300503T200000\r\nDURATION:PT1H\r\nEND:VEVENT\r\n",
+            "BEGIN:VEVENT\r\nSUMMARY:Moment\r\nDTSTART:20300503T200000Z\r\nEND:VEVENT\r\n",
+        ));
+        let (events, skipped) = parse_ics(&input, &BERLIN).unwrap();
+        assert!(events.is_empty());
+        assert_eq!(skipped.len(), 3);
+        assert!(skipped[0].starts_with("No start"));
+        assert!(parse_ics("SUMMARY:Not a calendar", &BERLIN).is_err());
+    }
+}
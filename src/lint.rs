@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::clock::{Clock, FixedClock};
+use crate::commonio::{ClosedData, ClosedStatus, EventKind};
+
+//How far ahead the timeline is simulated.
+const HORIZON_SECONDS: i64 = 90 * 86400;
+//Bounds the simulation for schedules that fire very often.
+const MAX_STEPS: usize = 5000;
+
+/// Something about the schedule that probably doesn't do what was intended.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Warning {
+    /// The status is set manually with no expiry, so none of the scheduled events have any effect.
+    ManualOverride { status: ClosedStatus },
+    /// There are close events but nothing ever opens the headless again.
+    NeverReopens,
+    /// There are open events but nothing ever closes the headless, so they have no effect.
+    NeverCloses,
+    /// An event has no occurrences at all, neither so far nor still to come.
+    NeverFires { event: EventKind, id: usize },
+    /// An event fires while the status is already what it would set, like two opens in a row.
+    Redundant { event: EventKind, id: usize, redundant: usize, total: usize, first: i64 },
+    /// Two closed windows (or one window with itself, when it repeats more often than it lasts) are active at the same time.
+    OverlappingWindows { first: usize, second: usize, at: i64 },
+}
+
+impl Warning {
+    pub fn describe(&self) -> String {
+        match self {
+            Warning::ManualOverride{status} => format!("The headless is manually set to {status:?}, so scheduled events have no effect until it's set back to Automatic"),
+            Warning::NeverReopens => "There are close events but no open events or closed windows, so the headless stays closed forever after the first close".to_string(),
+            Warning::NeverCloses => "There are open events but no close events or closed windows, so the open events have no effect".to_string(),
+            Warning::NeverFires{event, id} => format!("{event:?} event {id} never fires"),
+            Warning::Redundant{event, id, redundant, total, first} if redundant == total => {
+                format!("{event:?} event {id} never changes the status in the next 90 days, it always fires when the headless is already {}, first at <t:{first}:f>", state_after(*event))
+            },
+            Warning::Redundant{event, id, redundant, total, first} => {
+                format!("{event:?} event {id} fires {redundant} of {total} times in the next 90 days while the headless is already {}, first at <t:{first}:f>", state_after(*event))
+            },
+            Warning::OverlappingWindows{first, second, at} if first == second => format!("Closed window {first} starts again before it ends, first at <t:{at}:f>"),
+            Warning::OverlappingWindows{first, second, at} => format!("Closed windows {first} and {second} overlap, first at <t:{at}:f>"),
+        }
+    }
+
+    /// Whether the warning is about the schedule as a whole rather than specific events.
+    pub fn is_general(&self) -> bool {
+        matches!(self, Warning::ManualOverride{..} | Warning::NeverReopens | Warning::NeverCloses)
+    }
+
+    /// Whether the warning is about a particular event, rather than the schedule as a whole.
+    pub fn involves(&self, kind: EventKind, event_id: usize) -> bool {
+        match self {
+            Warning::NeverFires{event, id} | Warning::Redundant{event, id, ..} => *event == kind && *id == event_id,
+            Warning::OverlappingWindows{first, second, ..} => kind == EventKind::Window && (*first == event_id || *second == event_id),
+            Warning::ManualOverride{..} | Warning::NeverReopens | Warning::NeverCloses => false,
+        }
+    }
+}
+
+fn state_after(kind: EventKind) -> &'static str {
+    match kind {
+        EventKind::Open => "open",
+        EventKind::Close | EventKind::Window => "closed",
+    }
+}
+
+/// Checks the combined schedule for states that can't be reached, overlapping windows and events that never fire.
+pub fn lint_schedule(data: &ClosedData, clock: &impl Clock) -> Vec<Warning> {
+    let now = clock.now();
+    let holidays = &data.holidays;
+    let mut warnings: Vec<Warning> = Vec::new();
+
+    let manual = data.manual_status(clock);
+    let permanent_override = !matches!(manual, ClosedStatus::Automatic) && data.override_until.is_none();
+    if permanent_override {
+        warnings.push(Warning::ManualOverride{status: manual});
+    }
+
+    let active_close = data.close_events.values().filter(|x| x.enabled).count();
+    let active_open = data.open_events.values().filter(|x| x.enabled).count();
+    let active_windows = data.closed_windows.values().filter(|x| x.start.enabled).count();
+    if active_close > 0 && active_open == 0 && active_windows == 0 {
+        warnings.push(Warning::NeverReopens);
+    }
+    if active_open > 0 && active_close == 0 && active_windows == 0 {
+        warnings.push(Warning::NeverCloses);
+    }
+
+    let events = data.close_events.values().map(|x| (EventKind::Close, x))
+        .chain(data.open_events.values().map(|x| (EventKind::Open, x)))
+        .chain(data.closed_windows.values().map(|x| (EventKind::Window, &x.start)));
+    for (kind, event) in events {
+        //Cron expressions can match before they were added, but those occurrences never fired.
+        if event.enabled && event.next(now, holidays) == i64::MAX && event.most_recent(now, holidays) < event.initial {
+            warnings.push(Warning::NeverFires{event: kind, id: event.id});
+        }
+    }
+
+    let mut ids: Vec<usize> = data.closed_windows.keys().copied().collect();
+    ids.sort();
+    for (i, first) in ids.iter().enumerate() {
+        for second in &ids[i..] {
+            if let Some(at) = first_overlap(data, *first, *second, now) {
+                warnings.push(Warning::OverlappingWindows{first: *first, second: *second, at});
+            }
+        }
+    }
+
+    if !permanent_override {
+        //A timed override hides the schedule until it expires.
+        let from = data.override_until.map_or(now, |x| x.max(now));
+        warnings.extend(redundant_events(data, from));
+    }
+    return warnings;
+}
+
+/// First start of one window that falls inside the other within the horizon.
+fn first_overlap(data: &ClosedData, first: usize, second: usize, now: i64) -> Option<i64> {
+    let holidays = &data.holidays;
+    let a = data.closed_windows.get(&first)?;
+    let b = data.closed_windows.get(&second)?;
+    let end = now.saturating_add(HORIZON_SECONDS);
+
+    //Checking whether either window starts while the other is active finds every overlap.
+    let mut found: Option<i64> = None;
+    for (starting, other) in [(a, b), (b, a)] {
+        let mut at = now;
+        for _ in 0..MAX_STEPS {
+            let start = starting.next_start(at, holidays);
+            if start > end || found.is_some_and(|x| start >= x) {
+                break;
+            }
+            let overlaps = if first == second {
+                //A window overlaps itself if its previous start is still active.
+                other.start.most_recent(start - 1, holidays).saturating_add(other.duration) > start
+            } else {
+                other.is_active(start, holidays)
+            };
+            if overlaps {
+                found = Some(start);
+                break;
+            }
+            at = start;
+        }
+    }
+    return found;
+}
+
+/// Steps through every time an event fires within the horizon, finding events that fire without changing the status.
+fn redundant_events(data: &ClosedData, now: i64) -> Vec<Warning> {
+    let holidays = &data.holidays;
+    let end = now.saturating_add(HORIZON_SECONDS);
+    //(kind, id) -> (redundant, total, first redundant)
+    let mut counts: HashMap<(EventKind, usize), (usize, usize, i64)> = HashMap::new();
+
+    let mut at = now;
+    for _ in 0..MAX_STEPS {
+        let next = data.next_change(&FixedClock(at));
+        if next > end {
+            break;
+        }
+        let before = data.is_currently_closed(&FixedClock(next - 1));
+        let after = data.is_currently_closed(&FixedClock(next));
+
+        let firing = data.close_events.values().filter(|x| x.next(next - 1, holidays) == next).map(|x| (EventKind::Close, x.id))
+            .chain(data.open_events.values().filter(|x| x.next(next - 1, holidays) == next).map(|x| (EventKind::Open, x.id)))
+            .chain(data.closed_windows.values().filter(|x| x.next_start(next - 1, holidays) == next).map(|x| (EventKind::Window, x.id)));
+        for (kind, id) in firing {
+            let closes = kind != EventKind::Open;
+            let entry = counts.entry((kind, id)).or_insert((0, 0, i64::MAX));
+            entry.1 += 1;
+            if before == after || after != closes {
+                entry.0 += 1;
+                entry.2 = entry.2.min(next);
+            }
+        }
+        at = next;
+    }
+
+    let mut warnings: Vec<Warning> = counts.into_iter()
+        .filter(|(_, (redundant, _, _))| *redundant > 0)
+        .map(|((event, id), (redundant, total, first))| Warning::Redundant{event, id, redundant, total, first})
+        .collect();
+    warnings.sort_by_key(|x| match x {
+        Warning::Redundant{first, ..} => *first,
+        _ => i64::MAX,
+    });
+    return warnings;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repeat::{ClosedWindow, Recurrence, RepeatInterval, RepeatType, RepeatingEvent};
+
+    //Wednesday 2024-05-01 12:00 UTC.
+    const NOW: i64 = 1_714_564_800;
+    const HOUR: i64 = 3600;
+
+    fn event(id: usize, initial: i64, t: RepeatType) -> RepeatingEvent {
+        let mut event = RepeatingEvent::new(initial, Recurrence::Interval(RepeatInterval{t, n: 1}), None);
+        event.id = id;
+        event
+    }
+
+    /// Closes every evening at 20:00 and opens again at 23:00.
+    fn evenings() -> ClosedData {
+        let mut data = ClosedData::default();
+        data.close_events.insert(0, event(0, NOW + 8 * HOUR, RepeatType::Days));
+        data.open_events.insert(0, event(0, NOW + 11 * HOUR, RepeatType::Days));
+        return data;
+    }
+
+    fn lint(data: &ClosedData) -> Vec<Warning> {
+        lint_schedule(data, &FixedClock(NOW))
+    }
+
+    #[test]
+    fn clean_schedule() {
+        let mut data = evenings();
+        //Saturday afternoons, while the headless is open.
+        data.closed_windows.insert(0, ClosedWindow{id: 0, start: event(0, NOW + 3 * 86400, RepeatType::Weeks), duration: 2 * HOUR});
+        let warnings = lint(&data);
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+
+    #[test]
+    fn manual_override() {
+        let mut data = evenings();
+        data.is_closed = ClosedStatus::Closed;
+        let warnings = lint(&data);
+        assert!(matches!(warnings[..], [Warning::ManualOverride{status: ClosedStatus::Closed}]), "{warnings:?}");
+
+        //A timed override runs out, so it isn't reported.
+        data.override_until = Some(NOW + HOUR);
+        assert!(lint(&data).is_empty());
+    }
+
+    #[test]
+    fn never_reopens() {
+        let mut data = evenings();
+        data.open_events.clear();
+        let warnings = lint(&data);
+        assert!(warnings.iter().any(|x| matches!(x, Warning::NeverReopens)), "{warnings:?}");
+    }
+
+    #[test]
+    fn never_closes() {
+        let mut data = evenings();
+        data.close_events.clear();
+        let warnings = lint(&data);
+        assert!(warnings.iter().any(|x| matches!(x, Warning::NeverCloses)), "{warnings:?}");
+    }
+
+    #[test]
+    fn never_fires() {
+        let mut data = evenings();
+        let mut ended = event(1, NOW + HOUR, RepeatType::Days);
+        ended.until = Some(NOW);
+        data.close_events.insert(1, ended);
+        let warnings = lint(&data);
+        assert!(matches!(warnings[..], [Warning::NeverFires{event: EventKind::Close, id: 1}]), "{warnings:?}");
+
+        let mut data = evenings();
+        let mut cron = RepeatingEvent::new(NOW, Recurrence::Cron{cron: "0 12 * * *".to_string()}, None);
+        cron.id = 1;
+        cron.until = Some(NOW - HOUR);
+        data.open_events.insert(1, cron);
+        let warnings = lint(&data);
+        assert!(matches!(warnings[..], [Warning::NeverFires{event: EventKind::Open, id: 1}]), "{warnings:?}");
+    }
+
+    #[test]
+    fn redundant() {
+        let mut data = evenings();
+        //Always an hour after the headless already closed.
+        data.close_events.insert(1, event(1, NOW + 9 * HOUR, RepeatType::Days));
+        let warnings = lint(&data);
+        match warnings[..] {
+            [Warning::Redundant{event: EventKind::Close, id: 1, redundant, total, first}] => {
+                assert_eq!(redundant, total);
+                assert_eq!(first, NOW + 9 * HOUR);
+            },
+            _ => panic!("{warnings:?}"),
+        }
+    }
+
+    #[test]
+    fn overlapping_windows() {
+        let mut data = ClosedData::default();
+        data.closed_windows.insert(0, ClosedWindow{id: 0, start: event(0, NOW + 8 * HOUR, RepeatType::Days), duration: 2 * HOUR});
+        data.closed_windows.insert(1, ClosedWindow{id: 1, start: event(1, NOW + 9 * HOUR, RepeatType::Days), duration: 2 * HOUR});
+        let warnings = lint(&data);
+        assert!(warnings.iter().any(|x| matches!(x, Warning::OverlappingWindows{first: 0, second: 1, at} if *at == NOW + 9 * HOUR)), "{warnings:?}");
+
+        //Starting every hour but lasting two overlaps itself.
+        let mut data = ClosedData::default();
+        data.closed_windows.insert(2, ClosedWindow{id: 2, start: event(2, NOW + HOUR, RepeatType::Hours), duration: 2 * HOUR});
+        let warnings = lint(&data);
+        assert!(warnings.iter().any(|x| matches!(x, Warning::OverlappingWindows{first: 2, second: 2, ..})), "{warnings:?}");
+    }
+}
@@ -2,20 +2,36 @@ pub mod web;
 pub mod commonio;
 pub mod discord;
 pub mod repeat;
+pub mod cron;
+pub mod ics;
+pub mod clock;
+pub mod timeparse;
+pub mod lint;
+pub mod scheduler;
+pub mod whitelist;
 
 use std::thread;
 
 use crate::web::web;
 use crate::discord::discord;
+use crate::scheduler::{scheduler, Scheduler};
 
 fn main() {
+    let handle = Scheduler::new();
+
+    let discord_handle = handle.clone();
     let t1 = thread::spawn(|| {
-        discord();
+        discord(discord_handle);
     });
+    let web_handle = handle.clone();
     let t2 = thread::spawn(|| {
-        web();
+        web(web_handle);
+    });
+    let t3 = thread::spawn(|| {
+        scheduler(handle);
     });
 
     t1.join().unwrap();
     t2.join().unwrap();
+    t3.join().unwrap();
 }
\ No newline at end of file
@@ -1,6 +1,13 @@
-use chrono::{DateTime, Utc, Datelike};
+use chrono::{DateTime, Datelike, Duration, LocalResult, Months, NaiveDate, NaiveDateTime, Offset, TimeZone};
+use chrono_tz::Tz;
+use std::sync::OnceLock;
+
 use serde::{Serialize, Deserialize};
 
+use rrule::{RRule, RRuleSet, Unvalidated};
+
+use crate::cron::CronSchedule;
+
 #[derive(poise::ChoiceParameter, Clone, Copy, Serialize, Deserialize, Debug)]
 pub enum RepeatType {
     Seconds,
@@ -24,70 +31,466 @@ impl RepeatType {
 }
 
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Parses an IANA timezone name such as `Europe/London`.
+pub fn parse_timezone(name: &str) -> Result<Tz, String> {
+    name.trim().parse::<Tz>().map_err(|_| format!("Unknown timezone `{name}`, expected an IANA name like `America/New_York`"))
+}
+
+/// Converts a local wall clock time to a timestamp.
+/// Ambiguous times (when clocks go back) use the earlier instant, and times skipped when clocks go forward are pushed forward by the size of the gap.
+pub fn resolve_local(tz: &Tz, local: NaiveDateTime) -> i64 {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(dt) => dt.timestamp(),
+        LocalResult::Ambiguous(earliest, _latest) => earliest.timestamp(),
+        LocalResult::None => {
+            let offset_before = tz.offset_from_utc_datetime(&(local - Duration::days(1)));
+            tz.from_utc_datetime(&(local - offset_before.fix())).timestamp()
+        },
+    }
+}
+
+/// How an event repeats. Stored untagged so events saved before cron support still load as intervals.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Recurrence {
+    /// Every N units starting from the event's initial timestamp.
+    Interval(RepeatInterval),
+    /// Whenever a cron expression matches, in the event's timezone.
+    Cron { cron: String },
+    /// An RFC 5545 RRULE starting from the event's initial timestamp, minus any excluded occurrences.
+    RRule {
+        rrule: String,
+        #[serde(default)]
+        exdates: Vec<i64>,
+    },
+    /// Only at the event's initial timestamp. Stored as `null`.
+    Once,
+}
+
+impl Recurrence {
+    pub fn describe(&self) -> String {
+        match self {
+            Recurrence::Interval(interval) => format!("every {} {}", interval.n, interval.t.with_plurality(interval.n)),
+            Recurrence::Cron{cron} => format!("on cron `{cron}`"),
+            Recurrence::RRule{rrule, exdates} if exdates.is_empty() => format!("by rule `{rrule}`"),
+            Recurrence::RRule{rrule, exdates} => format!("by rule `{rrule}` except {} dates", exdates.len()),
+            Recurrence::Once => "once".to_string(),
+        }
+    }
+}
+
+//Stop looking for an occurrence that isn't skipped after this many, in case every occurrence is.
+const MAX_SKIPPED: usize = 1000;
+//Longer intervals can't be a real schedule, and far enough out they overflow timestamps.
+const MAX_INTERVAL_SECONDS: i64 = 1000 * 31556952;
+//Beyond this `Duration::days` panics rather than returning something `checked_add_signed` can reject.
+const MAX_DAYS: i64 = i64::MAX / 86_400_000;
+//Finding the last of a bounded cron or RRULE event steps through every occurrence, so events can't have many more than this.
+pub const MAX_COUNT: u32 = 10_000;
+
+/// Builds an interval recurrence from command options, where leaving out both N and the type means a one-off event.
+pub fn interval_or_once(n: Option<i64>, t: Option<RepeatType>) -> Result<Recurrence, String> {
+    match (n, t) {
+        (Some(n), Some(_)) if n <= 0 => Err("N must be at least 1".to_string()),
+        (Some(n), Some(t)) if average_seconds(RepeatInterval{t, n}) > MAX_INTERVAL_SECONDS => Err("Events can repeat at most every 1000 years".to_string()),
+        (Some(n), Some(t)) => Ok(Recurrence::Interval(RepeatInterval{t, n})),
+        (None, None) => Ok(Recurrence::Once),
+        _ => Err("Set both N and type for a repeating event, or neither for a one-off event".to_string()),
+    }
+}
+
+/// Builds the set of occurrences for an RRULE, which may be given with or without the `RRULE:` prefix.
+pub fn build_rrule_set(rule: &str, initial: i64, tz: &Tz, exdates: &[i64]) -> Result<RRuleSet, String> {
+    let rrule_tz = rrule::Tz::Tz(*tz);
+    let to_rrule_dt = |x: i64| DateTime::from_timestamp(x, 0).map(|x| x.with_timezone(&rrule_tz)).ok_or(format!("Timestamp {x} is out of range"));
+
+    let rule = rule.trim();
+    let rule = rule.strip_prefix("RRULE:").unwrap_or(rule);
+    let parsed: RRule<Unvalidated> = rule.parse().map_err(|e: rrule::RRuleError| e.to_string())?;
+    let mut set = parsed.build(to_rrule_dt(initial)?).map_err(|e| e.to_string())?;
+
+    for exdate in exdates {
+        set = set.exdate(to_rrule_dt(*exdate)?);
+    }
+    Ok(set)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RepeatingEvent {
     pub id: usize,
+    /// First occurrence for intervals and RRULEs. For cron events this is when the event was added.
     pub initial: i64,
-    pub repeating: RepeatInterval,
+    pub repeating: Recurrence,
+    /// IANA timezone that calendar based repeats (days and longer) and cron expressions are computed in. UTC if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// No occurrences after this timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<i64>,
+    /// No more than this many occurrences, counting from `initial`. Skipped occurrences still count.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u32>,
+    /// Local dates on which occurrences are skipped.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip_dates: Vec<NaiveDate>,
+    /// Short name shown alongside the id, like "Friday members night".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Longer explanation of what the event is for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Discord user ID of the admin who added the event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Paused events keep their id and settings but never fire.
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    /// `last_occurrence`, found the first time it's needed. Events are only edited straight after they're loaded or built.
+    #[serde(skip)]
+    last: OnceLock<Option<i64>>,
+}
+
+fn enabled_default() -> bool {
+    true
 }
 
 impl RepeatingEvent {
-    pub fn nth(&self, n: i64) -> i64 {
-        match self.repeating.t {
-            RepeatType::Months => {
-                let initial_dt: DateTime<Utc> = DateTime::from_timestamp(self.initial, 0).unwrap();
-                let pre_month = initial_dt.month() as i64 + (self.repeating.n * n);
-                let year = (initial_dt.year() as i64 + (pre_month / 12)) as i32;
-                let month = (pre_month % 12) as u32;
-                let new_dt = initial_dt.with_month(month).unwrap().with_year(year).unwrap();
-                return new_dt.timestamp();
+    /// An enabled event with no bounds, skipped dates, label or owner. Its id is 0 until it's added to the schedule.
+    pub fn new(initial: i64, repeating: Recurrence, timezone: Option<String>) -> Self {
+        return RepeatingEvent{
+            id: 0,
+            initial,
+            repeating,
+            timezone,
+            until: None,
+            count: None,
+            skip_dates: Vec::new(),
+            label: None,
+            description: None,
+            owner: None,
+            enabled: true,
+            last: OnceLock::new(),
+        };
+    }
+
+    pub fn tz(&self) -> Tz {
+        self.timezone.as_deref().and_then(|x| parse_timezone(x).ok()).unwrap_or(Tz::UTC)
+    }
+
+    /// Timestamp of the nth repeat, where the 0th is `initial`, or `i64::MAX` if it's too far away to represent.
+    ///
+    /// Days and weeks keep the same local wall clock time across DST changes.
+    /// Months and years are counted from `initial`, and days past the end of a shorter month are clamped to its last day,
+    /// so an event starting on January 31st repeats on February 28th (or 29th) and then March 31st.
+    pub fn nth(&self, interval: RepeatInterval, n: i64) -> i64 {
+        return self.checked_nth(interval, n).unwrap_or(i64::MAX);
+    }
+
+    /// `nth`, or `None` if the repeat is too far away to be represented.
+    fn checked_nth(&self, interval: RepeatInterval, n: i64) -> Option<i64> {
+        //Resolving the local time again would give the earlier instance if `initial` is in the hour repeated when DST ends.
+        if n == 0 {
+            return Some(self.initial);
+        }
+        let steps = interval.n.checked_mul(n)?;
+        let calendar_months = match interval.t {
+            RepeatType::Days | RepeatType::Weeks => None,
+            RepeatType::Months => Some(steps),
+            RepeatType::Years => Some(steps.checked_mul(12)?),
+            _ => return average_seconds(interval).checked_mul(n).and_then(|x| self.initial.checked_add(x)),
+        };
+
+        let tz = self.tz();
+        let local = DateTime::from_timestamp(self.initial, 0)?.with_timezone(&tz).naive_local();
+
+        let new_local = match calendar_months {
+            None => {
+                let days = if let RepeatType::Weeks = interval.t {steps.checked_mul(7)?} else {steps};
+                if days.unsigned_abs() > MAX_DAYS as u64 {
+                    return None;
+                }
+                local.checked_add_signed(Duration::days(days))?
             },
-            RepeatType::Years => {
-                let initial_dt: DateTime<Utc> = DateTime::from_timestamp(self.initial, 0).unwrap();
-                let year = (initial_dt.year() as i64 + (self.repeating.n * n)) as i32;
-                let new_dt = initial_dt.with_year(year).unwrap();
-                return new_dt.timestamp();
+            Some(months) if months >= 0 => local.checked_add_months(Months::new(u32::try_from(months).ok()?))?,
+            Some(months) => local.checked_sub_months(Months::new(u32::try_from(months.unsigned_abs()).ok()?))?,
+        };
+
+        return Some(resolve_local(&tz, new_local));
+    }
+    
+    /// Human readable description of how the event repeats, including its timezone and bounds if set.
+    pub fn describe(&self) -> String {
+        let tz_s = self.timezone.as_ref().map(|x| format!(" ({x})")).unwrap_or_default();
+        let until_s = self.until.map(|x| format!(" until <t:{x}:f>")).unwrap_or_default();
+        let count_s = self.count.map(|x| format!(" for {x} {}", if x == 1 {"occurrence"} else {"occurrences"})).unwrap_or_default();
+        let skip_s = if self.skip_dates.is_empty() {String::new()} else {
+            format!(" skipping {}", self.skip_dates.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", "))
+        };
+        let paused_s = if self.enabled {""} else {" (paused)"};
+        format!("{}{tz_s}{until_s}{count_s}{skip_s}{paused_s}", self.repeating.describe())
+    }
+
+    /// The id along with the label if there is one, like `3: Friday members night`.
+    pub fn title(&self) -> String {
+        match &self.label {
+            Some(label) => format!("{}: {label}", self.id),
+            None => self.id.to_string(),
+        }
+    }
+
+    /// The description and who added the event, each on its own line, or empty if neither is known.
+    pub fn details(&self) -> String {
+        let description_s = self.description.as_ref().map(|x| format!("\n{x}")).unwrap_or_default();
+        let owner_s = self.owner.as_ref().map(|x| format!("\nAdded by <@{x}>")).unwrap_or_default();
+        format!("{description_s}{owner_s}")
+    }
+
+    /// Whether an occurrence falls on one of the event's skip dates or a holiday, by local date in the event's timezone.
+    fn is_skipped(&self, occurrence: i64, holidays: &[NaiveDate]) -> bool {
+        if self.skip_dates.is_empty() && holidays.is_empty() {
+            return false;
+        }
+        let Some(date) = DateTime::from_timestamp(occurrence, 0).map(|x| x.with_timezone(&self.tz()).date_naive()) else {
+            return false;
+        };
+        return self.skip_dates.contains(&date) || holidays.contains(&date);
+    }
+
+    fn cron(&self) -> Option<CronSchedule> {
+        match &self.repeating {
+            Recurrence::Cron{cron} => CronSchedule::parse(cron).ok(),
+            _ => None,
+        }
+    }
+
+    fn rrule_set(&self) -> Option<RRuleSet> {
+        match &self.repeating {
+            Recurrence::RRule{rrule, exdates} => build_rrule_set(rrule, self.initial, &self.tz(), exdates).ok(),
+            _ => None,
+        }
+    }
+
+    /// Index of the latest repeat at or before `at`, or `None` if `at` is before `initial` or out of range.
+    ///
+    /// The index comes straight from the distance between `initial` and `at` in the unit being repeated.
+    /// Local wall clock times and clamped month days only ever put that estimate one repeat too far, which is corrected once.
+    fn index_at_or_before(&self, interval: RepeatInterval, at: i64) -> Option<i64> {
+        if at < self.initial {
+            return None;
+        }
+        if interval.n <= 0 {
+            return Some(0);
+        }
+
+        let (units, per_repeat) = match interval.t {
+            RepeatType::Seconds | RepeatType::Minutes | RepeatType::Hours => return Some(at.saturating_sub(self.initial) / average_seconds(interval)),
+            RepeatType::Days | RepeatType::Weeks | RepeatType::Months | RepeatType::Years => {
+                let tz = self.tz();
+                let initial_local = DateTime::from_timestamp(self.initial, 0)?.with_timezone(&tz).naive_local();
+                let at_local = DateTime::from_timestamp(at, 0)?.with_timezone(&tz).naive_local();
+                match interval.t {
+                    RepeatType::Days => ((at_local.date() - initial_local.date()).num_days(), interval.n),
+                    RepeatType::Weeks => ((at_local.date() - initial_local.date()).num_days(), interval.n.saturating_mul(7)),
+                    RepeatType::Months => (month_number(&at_local) - month_number(&initial_local), interval.n),
+                    _ => (month_number(&at_local) - month_number(&initial_local), interval.n.saturating_mul(12)),
+                }
+            },
+        };
+
+        //The estimated repeat lands in the same day or month as `at`, so it may be later that day or month.
+        let mut index = units.max(0) / per_repeat;
+        if index > 0 && self.nth(interval, index) > at {
+            index -= 1;
+        }
+        return Some(index);
+    }
+
+    /// Latest occurrence at or before `at` without applying `until` or `count`, or `i64::MIN` if there is none.
+    ///
+    /// Intervals and RRULEs have no occurrences before `initial`. Cron expressions aren't bounded by `initial`, which is only when they were added.
+    fn unbounded_most_recent(&self, at: i64) -> i64 {
+        let interval = match self.repeating {
+            Recurrence::Interval(interval) => interval,
+            Recurrence::Once => return if self.initial <= at {self.initial} else {i64::MIN},
+            Recurrence::Cron{..} => return self.cron().and_then(|x| x.last_at_or_before(&self.tz(), at)).unwrap_or(i64::MIN),
+            Recurrence::RRule{..} => {
+                return self.rrule_set()
+                    .and_then(|x| x.into_iter().map(|x| x.timestamp()).take_while(|x| *x <= at).last())
+                    .unwrap_or(i64::MIN);
+            },
+        };
+
+        match self.index_at_or_before(interval, at) {
+            Some(index) => self.nth(interval, index),
+            None => i64::MIN,
+        }
+    }
+
+    /// First occurrence after `at` without applying `until` or `count`, or `i64::MAX` if there is none.
+    fn unbounded_next(&self, at: i64) -> i64 {
+        let interval = match self.repeating {
+            Recurrence::Interval(interval) => interval,
+            Recurrence::Once => return if self.initial > at {self.initial} else {i64::MAX},
+            Recurrence::Cron{..} => return self.cron().and_then(|x| x.next_after(&self.tz(), at)).unwrap_or(i64::MAX),
+            Recurrence::RRule{..} => {
+                return self.rrule_set()
+                    .and_then(|x| x.into_iter().map(|x| x.timestamp()).find(|x| *x > at))
+                    .unwrap_or(i64::MAX);
+            },
+        };
+        if interval.n <= 0 {
+            return if self.initial > at {self.initial} else {i64::MAX};
+        }
+
+        match self.index_at_or_before(interval, at) {
+            Some(index) => self.nth(interval, index + 1),
+            None if at < self.initial => self.initial,
+            None => i64::MAX,
+        }
+    }
+
+    /// The final occurrence of a one-off or bounded event, `i64::MIN` if it never occurs at all, or `None` if it repeats forever.
+    pub fn last_occurrence(&self) -> Option<i64> {
+        return *self.last.get_or_init(|| self.find_last_occurrence());
+    }
+
+    fn find_last_occurrence(&self) -> Option<i64> {
+        let mut last: Option<i64> = match &self.repeating {
+            Recurrence::Once => Some(self.initial),
+            Recurrence::RRule{..} => {
+                let set = self.rrule_set()?;
+                let bounded = set.get_rrule().iter().all(|x| x.get_count().is_some() || x.get_until().is_some());
+                if bounded {
+                    Some(set.into_iter().map(|x| x.timestamp()).last().unwrap_or(i64::MIN))
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        if let Some(until) = self.until {
+            let bounded = self.unbounded_most_recent(until);
+            last = Some(last.map_or(bounded, |x| x.min(bounded)));
+        }
+
+        if let Some(count) = self.count {
+            let bounded = match self.repeating {
+                _ if count == 0 => i64::MIN,
+                Recurrence::Interval(interval) => self.nth(interval, count as i64 - 1),
+                Recurrence::RRule{..} => {
+                    self.rrule_set()
+                        .and_then(|x| x.into_iter().map(|x| x.timestamp()).take(count as usize).last())
+                        .unwrap_or(i64::MAX)
+                },
+                _ => {
+                    let mut occurrence = self.unbounded_next(self.initial - 1);
+                    for _ in 1..count {
+                        if occurrence == i64::MAX {
+                            break;
+                        }
+                        occurrence = self.unbounded_next(occurrence);
+                    }
+                    occurrence
+                },
+            };
+            last = Some(last.map_or(bounded, |x| x.min(bounded)));
+        }
+
+        return last;
+    }
+
+    /// Whether the event has no occurrences left.
+    pub fn is_finished(&self, now: i64) -> bool {
+        return self.last_occurrence().is_some_and(|x| x <= now);
+    }
+
+    /// Most recent occurrence at or before `now` that wasn't skipped, or `i64::MIN` if there is none.
+    pub fn most_recent(&self, now: i64, holidays: &[NaiveDate]) -> i64 {
+        if !self.enabled {
+            return i64::MIN;
+        }
+        let mut at = now.min(self.last_occurrence().unwrap_or(i64::MAX));
+
+        for _ in 0..MAX_SKIPPED {
+            let occurrence = self.unbounded_most_recent(at);
+            if occurrence == i64::MIN || !self.is_skipped(occurrence, holidays) {
+                return occurrence;
             }
-            _ => return self.initial + (average_seconds(self.repeating)* n),
+            at = occurrence - 1;
         }
+        return i64::MIN;
     }
-    
-    pub fn most_recent(&self) -> i64 {
-        let now: i64 = Utc::now().timestamp();
-        
-        //Use average number of seconds per repeat to get a good starting point, then iterate until nth(i + 1) is in the future.
-        let diff = now - self.initial;
-        let approx_n = diff / average_seconds(self.repeating);
-        let mut i = approx_n - 1;
-        
-        while self.nth(i+1) < now {
-            i+=1;
+
+    pub fn elapsed(&self, now: i64, holidays: &[NaiveDate]) -> i64 {
+        return now.saturating_sub(self.most_recent(now, holidays));
+    }
+
+    /// Next occurrence after `now` that won't be skipped, or `i64::MAX` if there is none.
+    pub fn next(&self, now: i64, holidays: &[NaiveDate]) -> i64 {
+        if !self.enabled {
+            return i64::MAX;
         }
+        let last = self.last_occurrence().unwrap_or(i64::MAX);
+        let mut at = now;
 
-        return self.nth(i);
+        for _ in 0..MAX_SKIPPED {
+            let occurrence = self.unbounded_next(at);
+            if occurrence > last {
+                return i64::MAX;
+            }
+            if !self.is_skipped(occurrence, holidays) {
+                return occurrence;
+            }
+            at = occurrence;
+        }
+        return i64::MAX;
     }
+}
+
+/// A recurring period during which the headless is closed, starting at each occurrence of `start` and lasting `duration` seconds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClosedWindow {
+    pub id: usize,
+    pub start: RepeatingEvent,
+    pub duration: i64,
+}
 
-    pub fn elapsed(&self) -> i64 {
-        let now: i64 = Utc::now().timestamp();
-        return now - self.most_recent();
+impl ClosedWindow {
+    /// Whether the most recent window started has not yet ended.
+    pub fn is_active(&self, now: i64, holidays: &[NaiveDate]) -> bool {
+        let most_recent = self.start.most_recent(now, holidays);
+        return most_recent <= now && now < most_recent.saturating_add(self.duration);
     }
 
-    pub fn next(&self) -> i64 {
-        let now: i64 = Utc::now().timestamp();
-        
-        //Use average number of seconds per repeat to get a good starting point, then iterate until nth(i - 1) is in the past.
-        let diff = now - self.initial;
-        let approx_n = diff / average_seconds(self.repeating);
-        let mut i = approx_n + 1;
-        
-        while self.nth(i-1) > now {
-            i-=1;
+    pub fn next_start(&self, now: i64, holidays: &[NaiveDate]) -> i64 {
+        return self.start.next(now, holidays);
+    }
+
+    /// Whether the last window has ended and no more will start.
+    pub fn is_finished(&self, now: i64) -> bool {
+        return self.start.last_occurrence().is_some_and(|x| x == i64::MIN || x.saturating_add(self.duration) <= now);
+    }
+
+    /// When the current window ends, or the next one if none is active.
+    pub fn next_end(&self, now: i64, holidays: &[NaiveDate]) -> i64 {
+        if self.is_active(now, holidays) {
+            return self.start.most_recent(now, holidays).saturating_add(self.duration);
         }
+        return self.start.next(now, holidays).saturating_add(self.duration);
+    }
 
-        return self.nth(i);
+    pub fn describe(&self) -> String {
+        let minutes = self.duration / 60;
+        format!("closed for {} {} {}", minutes, RepeatType::Minutes.with_plurality(minutes), self.start.describe())
     }
 }
 
+/// Months since year 0, for counting whole calendar months between two dates.
+fn month_number(local: &NaiveDateTime) -> i64 {
+    local.year() as i64 * 12 + local.month0() as i64
+}
+
 fn average_seconds(interval: RepeatInterval) -> i64 {
     let t_sec = match interval.t {
         RepeatType::Seconds => 1,
@@ -98,11 +501,132 @@ fn average_seconds(interval: RepeatInterval) -> i64 {
         RepeatType::Months => 2628288,
         RepeatType::Years => 31556952,
     };
-    return t_sec * interval.n;
+    return interval.n.saturating_mul(t_sec);
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct RepeatInterval {
     pub t: RepeatType,
     pub n: i64,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const TIMEZONES: [&str; 5] = ["UTC", "Europe/London", "America/New_York", "Australia/Lord_Howe", "Asia/Kolkata"];
+    const REPEAT_TYPES: [RepeatType; 7] = [RepeatType::Seconds, RepeatType::Minutes, RepeatType::Hours, RepeatType::Days, RepeatType::Weeks, RepeatType::Months, RepeatType::Years];
+
+    fn event(initial: i64, t: RepeatType, n: i64, timezone: &str) -> RepeatingEvent {
+        RepeatingEvent::new(initial, Recurrence::Interval(RepeatInterval{t, n}), Some(timezone.to_string()))
+    }
+
+    /// Walks every repeat from `initial` to find the latest at or before `at` and the first after it.
+    fn brute_force(event: &RepeatingEvent, at: i64) -> (i64, i64) {
+        let Recurrence::Interval(interval) = event.repeating else {
+            unreachable!();
+        };
+        let mut most_recent = i64::MIN;
+        let mut index = 0;
+        loop {
+            let occurrence = event.nth(interval, index);
+            if occurrence > at {
+                return (most_recent, occurrence);
+            }
+            most_recent = occurrence;
+            index += 1;
+        }
+    }
+
+    fn arb_event() -> impl Strategy<Value = RepeatingEvent> {
+        //2000-01-01 to 2040-01-01
+        (946_684_800i64..2_208_988_800, 0..REPEAT_TYPES.len(), 1i64..=12, 0..TIMEZONES.len())
+            .prop_map(|(initial, t, n, tz)| event(initial, REPEAT_TYPES[t], n, TIMEZONES[tz]))
+    }
+
+    /// Offsets from `initial` covering a few hundred repeats either side, so brute force stays quick.
+    fn arb_case() -> impl Strategy<Value = (RepeatingEvent, i64)> {
+        arb_event().prop_flat_map(|event| {
+            let Recurrence::Interval(interval) = event.repeating else {
+                unreachable!();
+            };
+            let span = average_seconds(interval) * 300;
+            (Just(event), -span..span)
+        }).prop_map(|(event, offset)| {
+            let at = event.initial + offset;
+            (event, at)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn matches_brute_force((event, at) in arb_case()) {
+            let (most_recent, next) = brute_force(&event, at);
+            prop_assert_eq!(event.most_recent(at, &[]), most_recent);
+            prop_assert_eq!(event.next(at, &[]), next);
+        }
+
+        #[test]
+        fn exact_on_occurrences(event in arb_event(), index in 0i64..300) {
+            let Recurrence::Interval(interval) = event.repeating else {
+                unreachable!();
+            };
+            let occurrence = event.nth(interval, index);
+            prop_assert_eq!(event.most_recent(occurrence, &[]), occurrence);
+            prop_assert_eq!(event.next(occurrence - 1, &[]), occurrence);
+            prop_assert_eq!(event.most_recent(occurrence - 1, &[]), brute_force(&event, occurrence - 1).0);
+        }
+
+        #[test]
+        fn nothing_before_initial(event in arb_event(), before in 1i64..100_000_000) {
+            let at = event.initial - before;
+            prop_assert_eq!(event.most_recent(at, &[]), i64::MIN);
+            prop_assert_eq!(event.next(at, &[]), event.initial);
+            prop_assert_eq!(event.elapsed(at, &[]), i64::MAX);
+        }
+
+        #[test]
+        fn huge_intervals_dont_panic(initial in 0i64..4_102_444_800, t in 0..REPEAT_TYPES.len(), n in 1i64..=i64::MAX, tz in 0..TIMEZONES.len(), offset in -1_000_000_000_000i64..1_000_000_000_000) {
+            let event = event(initial, REPEAT_TYPES[t], n, TIMEZONES[tz]);
+            let at = initial + offset;
+            let most_recent = event.most_recent(at, &[]);
+            let next = event.next(at, &[]);
+            prop_assert!(most_recent <= at && next > at);
+            event.elapsed(at, &[]);
+        }
+    }
+
+    #[test]
+    fn largest_interval_doesnt_overflow() {
+        let event = event(1_700_000_000, RepeatType::Years, i64::MAX, "Europe/London");
+        assert_eq!(event.next(1_700_000_000, &[]), i64::MAX);
+        assert_eq!(event.most_recent(4_000_000_000, &[]), 1_700_000_000);
+        assert!(interval_or_once(Some(1001), Some(RepeatType::Years)).is_err());
+        assert!(interval_or_once(Some(1000), Some(RepeatType::Years)).is_ok());
+        assert!(interval_or_once(Some(i64::MAX), Some(RepeatType::Seconds)).is_err());
+    }
+
+    #[test]
+    fn count_bounds_cron_and_rrule() {
+        const DAY: i64 = 86_400;
+        let initial = 1_714_564_800;
+        let mut cron = RepeatingEvent::new(initial, Recurrence::Cron{cron: "0 12 * * *".to_string()}, None);
+        cron.count = Some(3);
+        assert_eq!(cron.last_occurrence(), Some(initial + 2 * DAY));
+        assert_eq!(cron.next(initial + 2 * DAY, &[]), i64::MAX);
+
+        let mut rrule = RepeatingEvent::new(initial, Recurrence::RRule{rrule: "FREQ=DAILY".to_string(), exdates: Vec::new()}, None);
+        rrule.count = Some(3);
+        assert_eq!(rrule.last_occurrence(), Some(initial + 2 * DAY));
+        assert_eq!(rrule.most_recent(initial + 10 * DAY, &[]), initial + 2 * DAY);
+    }
+
+    #[test]
+    fn starts_in_repeated_dst_hour() {
+        //01:28 EST on 2007-11-04, the second time New York clocks showed 01:28 that night.
+        let event = event(1_194_157_702, RepeatType::Days, 9, "America/New_York");
+        assert_eq!(event.most_recent(1_194_157_701, &[]), i64::MIN);
+        assert_eq!(event.next(1_194_157_701, &[]), 1_194_157_702);
+        assert_eq!(event.most_recent(1_194_157_702, &[]), 1_194_157_702);
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use fs4::tokio::AsyncFileExt;
+
+use tokio::sync::{broadcast, watch, Notify};
+
+use crate::clock::{Clock, SystemClock};
+use crate::commonio::*;
+use crate::discord::common::load_timezone;
+
+//Wake up at least this often, so edits to closed.json made outside the bot are still picked up.
+const MAX_SLEEP_SECONDS: i64 = 300;
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Counters exposed on `/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    pub closed: AtomicBool,
+    pub transitions_to_closed: AtomicU64,
+    pub transitions_to_open: AtomicU64,
+    pub last_transition: AtomicI64,
+    pub next_transition: AtomicI64,
+    pub webhook_failures: AtomicU64,
+}
+
+/// Shared handle to the background scheduler, cloned into the Discord bot and the web server.
+///
+/// The scheduler sleeps until the next scheduled event and sends a `Transition` to every subscriber whenever the closed status changes.
+#[derive(Clone)]
+pub struct Scheduler {
+    transitions: broadcast::Sender<Transition>,
+    changed: Arc<Notify>,
+    //Counts edits to closed.json, for tasks other than the scheduler that follow the schedule.
+    edits: Arc<watch::Sender<u64>>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        let (transitions, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let (edits, _) = watch::channel(0);
+        Scheduler{transitions, changed: Arc::new(Notify::new()), edits: Arc::new(edits), metrics: Arc::new(Metrics::default())}
+    }
+
+    /// Receives every transition from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Transition> {
+        self.transitions.subscribe()
+    }
+
+    /// Notified every time closed.json is written by the bot.
+    pub fn watch_edits(&self) -> watch::Receiver<u64> {
+        self.edits.subscribe()
+    }
+
+    /// Wakes the scheduler to re-read closed.json, call after writing it.
+    pub fn schedule_changed(&self) {
+        self.changed.notify_one();
+        self.edits.send_modify(|x| *x = x.wrapping_add(1));
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// URLs from `TRANSITION_WEBHOOKS`, comma separated, that each transition is POSTed to as JSON.
+fn webhook_urls() -> Vec<String> {
+    std::env::var("TRANSITION_WEBHOOKS").unwrap_or_default()
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+async fn send_webhooks(scheduler: Scheduler, urls: Vec<String>, transition: Transition) {
+    let tz = match load_timezone().await {
+        Ok(tz) => tz,
+        Err(e) => {
+            println!("webhook timezone:{e:?}");
+            chrono_tz::UTC
+        },
+    };
+    let transition = transition.localized(&tz);
+    let client = reqwest::Client::new();
+    for url in urls {
+        let result = client.post(&url).json(&transition).timeout(Duration::from_secs(10)).send().await
+            .and_then(|x| x.error_for_status());
+        if let Err(e) = result {
+            scheduler.metrics.webhook_failures.fetch_add(1, Ordering::Relaxed);
+            println!("webhook {url}:{e:?}");
+        }
+    }
+}
+
+/// Why the status changed from the `previous` recorded change to `cause` at `now`.
+fn history_reason(previous: Option<&HistoryEntry>, cause: &ModeCause, now: i64) -> HistoryReason {
+    if matches!(cause, ModeCause::Manual{..}) {
+        return HistoryReason::Manual;
+    }
+    match previous.map(|x| x.cause) {
+        Some(ModeCause::Manual{until: Some(until), ..}) if until <= now => HistoryReason::OverrideExpired,
+        Some(ModeCause::Manual{..}) => HistoryReason::Manual,
+        _ => HistoryReason::Schedule,
+    }
+}
+
+/// Appends to history.json if the status or whether it's set manually differs from the last recorded change.
+async fn record_history(data: &ClosedData, closed: bool, cause: ModeCause, now: i64, first: bool) -> Result<(), Error> {
+    let (file_path, file, mut history) = load_json::<HistoryLog>(None, "history.json".to_string(), false).await?;
+
+    let manual = matches!(cause, ModeCause::Manual{..});
+    let previous = history.last();
+    if previous.is_some_and(|x| x.closed == closed && x.is_manual() == manual) {
+        file.unlock()?;
+        return Ok(());
+    }
+
+    let reason = if first {HistoryReason::Startup} else {history_reason(previous, &cause, now)};
+    history.push(HistoryEntry{at: now, closed, cause, label: data.cause_label(&cause).map(|x| x.to_string()), reason});
+    write_tmp_and_copy(None, &file_path, file, &serde_json::to_string(&history)?).await?;
+    Ok(())
+}
+
+/// Works out the current status and when it next might change, sending a transition if it changed since `last`.
+async fn evaluate(scheduler: &Scheduler, last: Option<bool>) -> Result<(bool, i64), Error> {
+    let (_file_path, file, data) = load_json::<ClosedData>(None, "closed.json".to_string(), true).await?;
+    file.unlock()?;
+
+    let now = SystemClock.now();
+    let closed = data.is_currently_closed(&SystemClock);
+    let next = data.next_change(&SystemClock);
+
+    let metrics = &scheduler.metrics;
+    metrics.closed.store(closed, Ordering::Relaxed);
+    metrics.next_transition.store(if next == i64::MAX {0} else {next}, Ordering::Relaxed);
+
+    if let Err(e) = record_history(&data, closed, data.mode_cause(&SystemClock), now, last.is_none()).await {
+        println!("record_history:{e:?}");
+    }
+
+    //The first evaluation only records the status, since nothing changed while the bot was watching.
+    if last.is_some_and(|x| x != closed) {
+        let cause = data.mode_cause(&SystemClock);
+        let transition = Transition{at: now, closed, cause, label: data.cause_label(&cause).map(|x| x.to_string())};
+
+        if closed {
+            metrics.transitions_to_closed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            metrics.transitions_to_open.fetch_add(1, Ordering::Relaxed);
+        }
+        metrics.last_transition.store(now, Ordering::Relaxed);
+
+        let urls = webhook_urls();
+        if !urls.is_empty() {
+            tokio::spawn(send_webhooks(scheduler.clone(), urls, transition.clone()));
+        }
+        //Only fails when nobody is subscribed, which is fine.
+        let _ = scheduler.transitions.send(transition);
+    }
+
+    Ok((closed, next))
+}
+
+/// Runs the scheduler until the process exits.
+#[tokio::main]
+pub async fn scheduler(scheduler: Scheduler) {
+    let mut last: Option<bool> = None;
+    loop {
+        let sleep_for = match evaluate(&scheduler, last).await {
+            Ok((closed, next)) => {
+                last = Some(closed);
+                //Events fire on whole seconds, so wake just as the next one is due.
+                next.saturating_sub(SystemClock.now()).clamp(0, MAX_SLEEP_SECONDS)
+            },
+            Err(e) => {
+                println!("scheduler:{e:?}");
+                MAX_SLEEP_SECONDS
+            },
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(sleep_for as u64)) => (),
+            _ = scheduler.changed.notified() => (),
+        }
+    }
+}
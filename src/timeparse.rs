@@ -0,0 +1,369 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use chrono_tz::Tz;
+
+use crate::repeat::resolve_local;
+
+//Anything this large is in milliseconds, as seconds it would be after the year 5000.
+const MILLISECONDS_THRESHOLD: i64 = 100_000_000_000;
+
+//3000-01-01, anything later is a typo rather than a schedule.
+const MAX_TIMESTAMP: i64 = 32_503_680_000;
+
+//Keeps relative amounts well inside what chrono can represent.
+const MAX_RELATIVE: i64 = 1_000_000;
+
+const NAIVE_FORMATS: [&str; 4] = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"];
+
+/// Parses a point in time typed by a user into a Unix timestamp.
+///
+/// Accepts Unix timestamps in seconds or milliseconds, Discord `<t:...>` markup, ISO 8601 with or without an offset,
+/// and phrases like `in 2 hours`, `tomorrow 20:00` or `next friday 8pm`. Anything without an offset is read in `tz`.
+/// Times before 1970 or from the year 3000 on are rejected.
+pub fn parse_time(input: &str, tz: &Tz, now: i64) -> Result<i64, String> {
+    let time = parse_unbounded(input, tz, now)?;
+    if !(0..MAX_TIMESTAMP).contains(&time) {
+        return Err(format!("`{}` is out of range, times must be between 1970 and the year 3000", input.trim()));
+    }
+    Ok(time)
+}
+
+fn parse_unbounded(input: &str, tz: &Tz, now: i64) -> Result<i64, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("No time given".to_string());
+    }
+
+    if let Ok(value) = input.parse::<i64>() {
+        return Ok(from_unix(value));
+    }
+
+    if let Some(markup) = input.strip_prefix("<t:").and_then(|x| x.strip_suffix('>')) {
+        let value = markup.split(':').next().unwrap_or_default();
+        return value.parse::<i64>().map(from_unix).map_err(|_| format!("`{input}` is not valid Discord timestamp markup"));
+    }
+
+    //RFC 3339 requires seconds, so also accept an offset straight after the minutes.
+    let iso = input.replacen(' ', "T", 1);
+    if let Ok(time) = DateTime::parse_from_rfc3339(&iso) {
+        return Ok(time.timestamp());
+    }
+    let iso = match iso.strip_suffix(['Z', 'z']) {
+        Some(stripped) => format!("{stripped}+00:00"),
+        None => iso,
+    };
+    for format in ["%Y-%m-%dT%H:%M:%S%z", "%Y-%m-%dT%H:%M%z"] {
+        if let Ok(time) = DateTime::parse_from_str(&iso, format) {
+            return Ok(time.timestamp());
+        }
+    }
+    for format in NAIVE_FORMATS {
+        if let Ok(time) = NaiveDateTime::parse_from_str(input, format) {
+            return Ok(resolve_local(tz, time));
+        }
+    }
+
+    let lower = input.to_lowercase();
+    let words: Vec<&str> = lower.split_whitespace().filter(|x| *x != "at" && *x != "on").collect();
+    if let Some(time) = parse_relative(&words, tz, now) {
+        return Ok(time);
+    }
+    if let Some(time) = parse_phrase(&words, tz, now) {
+        return Ok(time);
+    }
+
+    Err(format!("Couldn't understand `{input}`, try a Unix timestamp, ISO 8601 like `2024-05-03T20:00`, or a phrase like `next friday 20:00`"))
+}
+
+/// A Unix timestamp in seconds, or in milliseconds if it's too large to be seconds.
+fn from_unix(value: i64) -> i64 {
+    if value.unsigned_abs() >= MILLISECONDS_THRESHOLD as u64 {
+        return value / 1000;
+    }
+    return value;
+}
+
+fn local_now(tz: &Tz, now: i64) -> Option<NaiveDateTime> {
+    Some(DateTime::from_timestamp(now, 0)?.with_timezone(tz).naive_local())
+}
+
+/// `now`, `in 90 minutes`, `2 days from now`.
+fn parse_relative(words: &[&str], tz: &Tz, now: i64) -> Option<i64> {
+    let amount_unit = match words {
+        ["now"] => return Some(now),
+        ["in", amount, unit] => (amount, unit),
+        [amount, unit, "from", "now"] => (amount, unit),
+        _ => return None,
+    };
+    let amount = amount_unit.0.parse::<i64>().ok().filter(|x| x.abs() <= MAX_RELATIVE)?;
+    let unit = amount_unit.1.trim_end_matches('s');
+
+    let seconds = match unit {
+        "second" | "sec" => amount,
+        "minute" | "min" | "m" => amount * 60,
+        "hour" | "hr" | "h" => amount * 3600,
+        //Days and weeks keep the same wall clock time over DST changes.
+        "day" | "d" => return Some(resolve_local(tz, local_now(tz, now)?.checked_add_signed(Duration::days(amount))?)),
+        "week" | "w" => return Some(resolve_local(tz, local_now(tz, now)?.checked_add_signed(Duration::weeks(amount))?)),
+        _ => return None,
+    };
+    now.checked_add(seconds)
+}
+
+/// A day, a time of day, or a day followed by a time, e.g. `friday`, `20:00`, `next sat 8:30pm`, `2024-05-03 9pm`.
+fn parse_phrase(words: &[&str], tz: &Tz, now: i64) -> Option<i64> {
+    let local = local_now(tz, now)?;
+    let today = local.date();
+
+    let (day, rest): (Option<NaiveDate>, &[&str]) = match words {
+        ["today", rest @ ..] => (Some(today), rest),
+        ["tomorrow", rest @ ..] => (Some(today.succ_opt()?), rest),
+        ["next", day, rest @ ..] => (Some(next_weekday(today, parse_weekday(day)?, false)), rest),
+        [day, rest @ ..] if parse_weekday(day).is_some() => (Some(next_weekday(today, parse_weekday(day)?, true)), rest),
+        [day, rest @ ..] if NaiveDate::parse_from_str(day, "%Y-%m-%d").is_ok() => (NaiveDate::parse_from_str(day, "%Y-%m-%d").ok(), rest),
+        rest => (None, rest),
+    };
+
+    let time = match rest {
+        [] => None,
+        [time] => Some(parse_time_of_day(time)?),
+        [time, meridiem] => Some(parse_time_of_day(&format!("{time}{meridiem}"))?),
+        _ => return None,
+    };
+
+    match (day, time) {
+        (None, None) => None,
+        (Some(day), time) => {
+            let resolved = resolve_local(tz, day.and_time(time.unwrap_or(NaiveTime::MIN)));
+            //A bare weekday that already passed today means next week.
+            if resolved <= now && words.first().and_then(|x| parse_weekday(x)).is_some() {
+                return Some(resolve_local(tz, (day + Duration::weeks(1)).and_time(time.unwrap_or(NaiveTime::MIN))));
+            }
+            Some(resolved)
+        },
+        //A time on its own is the next time the clock reads that.
+        (None, Some(time)) => {
+            let resolved = resolve_local(tz, today.and_time(time));
+            if resolved > now {
+                return Some(resolved);
+            }
+            Some(resolve_local(tz, today.succ_opt()?.and_time(time)))
+        },
+    }
+}
+
+fn parse_weekday(word: &str) -> Option<Weekday> {
+    let weekday = match word.get(..3)? {
+        "mon" => Weekday::Mon,
+        "tue" => Weekday::Tue,
+        "wed" => Weekday::Wed,
+        "thu" => Weekday::Thu,
+        "fri" => Weekday::Fri,
+        "sat" => Weekday::Sat,
+        "sun" => Weekday::Sun,
+        _ => return None,
+    };
+    //Reject words that only start like a weekday, like `month`.
+    if !full_weekday(weekday).starts_with(word) {
+        return None;
+    }
+    Some(weekday)
+}
+
+fn full_weekday(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => "monday",
+        Weekday::Tue => "tuesday",
+        Weekday::Wed => "wednesday",
+        Weekday::Thu => "thursday",
+        Weekday::Fri => "friday",
+        Weekday::Sat => "saturday",
+        Weekday::Sun => "sunday",
+    }
+}
+
+/// The first date falling on `weekday` after `today`, or including `today` if `include_today` is set.
+fn next_weekday(today: NaiveDate, weekday: Weekday, include_today: bool) -> NaiveDate {
+    let mut days = (7 + weekday.num_days_from_monday() as i64 - today.weekday().num_days_from_monday() as i64) % 7;
+    if days == 0 && !include_today {
+        days = 7;
+    }
+    today + Duration::days(days)
+}
+
+/// `20:00`, `20:00:30`, `8pm`, `8:30pm`, `20`.
+fn parse_time_of_day(word: &str) -> Option<NaiveTime> {
+    let (clock, offset) = if let Some(clock) = word.strip_suffix("am") {
+        (clock, Some(0))
+    } else if let Some(clock) = word.strip_suffix("pm") {
+        (clock, Some(12))
+    } else {
+        (word, None)
+    };
+
+    let mut parts = clock.split(':');
+    let mut hour = parts.next()?.parse::<u32>().ok()?;
+    let minute = parts.next().map(|x| x.parse::<u32>().ok()).unwrap_or(Some(0))?;
+    let second = parts.next().map(|x| x.parse::<u32>().ok()).unwrap_or(Some(0))?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    if let Some(offset) = offset {
+        if hour == 0 || hour > 12 {
+            return None;
+        }
+        hour = hour % 12 + offset;
+    }
+    NaiveTime::from_hms_opt(hour, minute, second)
+}
+
+/// Parses a length of time like `2h`, `90m`, `1h30m`, `2 days` or `45` (minutes) into seconds.
+pub fn parse_duration(input: &str) -> Result<i64, String> {
+    let input = input.trim().to_lowercase();
+    let invalid = || format!("Couldn't understand `{input}` as a length of time, try something like `2h`, `90m` or `1d`");
+
+    if let Ok(minutes) = input.parse::<i64>() {
+        return if minutes > 0 && minutes <= MAX_RELATIVE {Ok(minutes * 60)} else {Err(invalid())};
+    }
+
+    let mut total: i64 = 0;
+    let mut rest = input.as_str();
+    while !rest.is_empty() {
+        let digits = rest.find(|x: char| !x.is_ascii_digit()).unwrap_or(rest.len());
+        let amount = rest[..digits].parse::<i64>().ok().filter(|x| *x <= MAX_RELATIVE).ok_or_else(invalid)?;
+        rest = rest[digits..].trim_start();
+
+        let letters = rest.find(|x: char| !x.is_ascii_alphabetic()).unwrap_or(rest.len());
+        let unit_seconds = match rest[..letters].trim_end_matches('s') {
+            "" | "m" | "min" | "minute" => 60,
+            "h" | "hr" | "hour" => 3600,
+            "d" | "day" => 86400,
+            "w" | "week" => 604800,
+            _ => return Err(invalid()),
+        };
+        rest = rest[letters..].trim_start_matches([' ', ',']);
+        total += amount * unit_seconds;
+    }
+
+    if total <= 0 {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+/// Reads when something should stop from a `for` length of time or an `until` time, where leaving out both means never.
+pub fn parse_expiry(duration: Option<&str>, until: Option<&str>, tz: &Tz, now: i64) -> Result<Option<i64>, String> {
+    let expires_at = match (duration, until) {
+        (Some(_), Some(_)) => return Err("Use either `for` or `until`, not both".to_string()),
+        (Some(duration), None) => now + parse_duration(duration)?,
+        (None, Some(until)) => parse_time(until, tz, now)?,
+        (None, None) => return Ok(None),
+    };
+    if expires_at <= now {
+        return Err(format!("<t:{expires_at}:f> is in the past"));
+    }
+    Ok(Some(expires_at))
+}
+
+/// Formats a timestamp as RFC 3339 in `tz`, like `2024-05-03T20:00:00+02:00`, for outputs that aren't rendered by Discord.
+pub fn format_local(at: i64, tz: &Tz) -> String {
+    match DateTime::from_timestamp(at, 0) {
+        Some(dt) => dt.with_timezone(tz).to_rfc3339(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Wednesday 2024-05-01 12:00 UTC, 14:00 in Berlin.
+    const NOW: i64 = 1_714_564_800;
+    //Friday 2024-05-03 20:00 in Berlin.
+    const FRIDAY_EVENING: i64 = 1_714_759_200;
+
+    fn berlin(input: &str) -> Result<i64, String> {
+        parse_time(input, &chrono_tz::Europe::Berlin, NOW)
+    }
+
+    #[test]
+    fn unix_timestamps() {
+        assert_eq!(berlin("1714759200"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin(" 1714759200000 "), Ok(FRIDAY_EVENING));
+        assert!(berlin("-9223372036854775808").is_err());
+    }
+
+    #[test]
+    fn discord_markup() {
+        assert_eq!(berlin("<t:1714759200>"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin("<t:1714759200:F>"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin("<t:1714759200000:R>"), Ok(FRIDAY_EVENING));
+        assert!(berlin("<t:soon>").is_err());
+    }
+
+    #[test]
+    fn iso_with_offset() {
+        assert_eq!(berlin("2024-05-03T20:00:00+02:00"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin("2024-05-03T20:00+02:00"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin("2024-05-03 18:00Z"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin("2024-05-03T15:00:00-03:00"), Ok(FRIDAY_EVENING));
+    }
+
+    #[test]
+    fn iso_in_timezone() {
+        assert_eq!(berlin("2024-05-03T20:00"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin("2024-05-03 20:00:00"), Ok(FRIDAY_EVENING));
+        assert_eq!(parse_time("2024-05-03 18:00", &chrono_tz::UTC, NOW), Ok(FRIDAY_EVENING));
+        //Skipped by the spring DST change, so read with the offset from before it.
+        assert_eq!(berlin("2024-03-31T02:30"), Ok(1_711_848_600));
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(berlin("now"), Ok(NOW));
+        assert_eq!(berlin("in 2 hours"), Ok(NOW + 7200));
+        assert_eq!(berlin("in 90 min"), Ok(NOW + 5400));
+        assert_eq!(berlin("3 days from now"), Ok(NOW + 3 * 86400));
+        assert_eq!(berlin("in 1 week"), Ok(NOW + 604800));
+        assert!(berlin("in 2 fortnights").is_err());
+    }
+
+    #[test]
+    fn phrases() {
+        assert_eq!(berlin("next friday 8pm"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin("Friday at 20:00"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin("fri 8:00 pm"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin("2024-05-03 8pm"), Ok(FRIDAY_EVENING));
+        assert_eq!(berlin("tomorrow 20:00"), Ok(1_714_672_800));
+        assert_eq!(berlin("today"), Ok(1_714_514_400));
+        //Already past today, so next week.
+        assert_eq!(berlin("wednesday 10:00"), Ok(1_715_155_200));
+        assert!(berlin("month 10:00").is_err());
+    }
+
+    #[test]
+    fn time_of_day() {
+        assert_eq!(berlin("15:30"), Ok(1_714_570_200));
+        //Already past today, so tomorrow.
+        assert_eq!(berlin("1pm"), Ok(1_714_647_600));
+        assert!(berlin("13pm").is_err());
+        assert!(berlin("25:00").is_err());
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert!(berlin("-5").is_err());
+        assert!(berlin("<t:-100:f>").is_err());
+        assert!(berlin("1969-12-31T23:00Z").is_err());
+        assert!(berlin("3000-01-02T00:00Z").is_err());
+        assert!(berlin("99999999999").is_err());
+        assert!(berlin("in 1000000 weeks").is_err());
+    }
+
+    #[test]
+    fn garbage() {
+        assert!(berlin("").is_err());
+        assert!(berlin("whenever").is_err());
+        assert!(berlin("2024-13-01T20:00").is_err());
+    }
+}
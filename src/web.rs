@@ -1,3 +1,6 @@
+pub mod ratelimit;
+
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
@@ -5,17 +8,35 @@ use fs4::tokio::AsyncFileExt;
 
 use tokio::io::{BufReader,AsyncBufReadExt, AsyncReadExt};
 
+use serde::{Serialize, Deserialize};
+
+use crate::clock::{Clock, FixedClock, SystemClock};
 use crate::commonio::*;
+use crate::whitelist::{WhitelistSnapshot, Decision, Explanation};
+use crate::discord::common::load_timezone;
+use crate::timeparse::format_local;
+use ratelimit::RateLimiter;
+
+use std::convert::Infallible;
+use std::sync::atomic::Ordering;
+
+use tokio_stream::{Stream, StreamExt, wrappers::BroadcastStream};
+
+use crate::scheduler::Scheduler;
 
 use axum::{
-    extract::Path,
-    routing::get,
+    extract::{Path, Query, State},
+    Json,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Response, sse::{Event, KeepAlive, Sse}},
+    routing::{get, post},
     Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
 
 #[tokio::main]
-pub async fn web() {
+pub async fn web(scheduler: Scheduler) {
     // initialize tracing
     //tracing_subscriber::fmt::init();
 
@@ -30,39 +51,226 @@ pub async fn web() {
     let app = Router::new()
         // `GET /` goes to `root`
         .route("/", get(root))
+        .route("/check", post(check_batch))
         .route("/check/:userid", get(is_whitelisted))
+        .route("/explain/:userid", get(explain))
         .route("/open", get(open))
         .route("/open/check/:userid", get(open_is_whitelisted))
         .route("/closed", get(closed))
-        .route("/closed/check/:userid", get(closed_is_whitelisted));
-        
+        .route("/closed/check/:userid", get(closed_is_whitelisted))
+        .route("/changes", get(changes))
+        .route("/status", get(status))
+        .route("/history", get(history))
+        .route("/events", get(events))
+        .route("/metrics", get(metrics))
+        .layer(middleware::from_fn_with_state(RateLimiter::from_env(), ratelimit::limit))
+        .with_state(scheduler);
+
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 2096));
     axum_server::bind_rustls(addr, config)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
-async fn root() -> String {
+/// Computes a strong ETag for a response body using 64 bit FNV-1a, so it stays stable across restarts.
+fn etag_for(body: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in body.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("\"{hash:016x}\"")
+}
+
+/// Responds with the body and its ETag, or with 304 Not Modified if the client's `If-None-Match` already matches.
+fn with_etag(headers: &HeaderMap, body: String) -> Response {
+    let etag = etag_for(&body);
+    let etag_value = HeaderValue::from_str(&etag).unwrap();
+
+    let matches = headers.get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .any(|x| x.trim() == etag || x.trim() == "*");
+
+    let mut response = if matches {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        body.into_response()
+    };
+    response.headers_mut().insert(header::ETAG, etag_value);
+    return response;
+}
+
+async fn root(headers: HeaderMap) -> Response {
     let (_file_path, _file, data) = load_json::<ClosedData>(None,"closed.json".to_string(),true).await.unwrap();
-    if data.is_currently_closed() {
-        return closed().await;
+    if data.is_currently_closed(&SystemClock) {
+        return with_etag(&headers, closed_list().await);
     } else {
-        return open().await;
+        return with_etag(&headers, open_list().await);
     }
 }
 
 async fn is_whitelisted(Path(userid): Path<String>) -> String {
     let (_file_path, _file, data) = load_json::<ClosedData>(None,"closed.json".to_string(),true).await.unwrap();
-    if data.is_currently_closed() {
+    if data.is_currently_closed(&SystemClock) {
         return closed_is_whitelisted(Path::<String>(userid)).await;
     } else {
         return open_is_whitelisted(Path::<String>(userid)).await;
     }
 }
 
-async fn open() -> String {
+/// Checks many UserIDs at once against a single snapshot of the closed status and whitelists.
+async fn check_batch(Json(userids): Json<Vec<String>>) -> Json<HashMap<String, Decision>> {
+    let snapshot = WhitelistSnapshot::load().await.unwrap();
+
+    let decisions = userids.into_iter()
+        .map(|userid| {
+            let decision = snapshot.check(&userid);
+            (userid, decision)
+        })
+        .collect();
+
+    return Json(decisions);
+}
+
+/// Reports the effective mode, list membership and final decision for a UserID.
+async fn explain(Path(userid): Path<String>) -> Json<Explanation> {
+    let snapshot = WhitelistSnapshot::load().await.unwrap();
+    return Json(snapshot.explain(&userid));
+}
+
+#[derive(Deserialize)]
+struct StatusQuery {
+    at: Option<i64>,
+    count: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    at: i64,
+    at_local: String,
+    timezone: String,
+    closed: bool,
+    cause: ModeCause,
+    label: Option<String>,
+    transitions: Vec<Localized<Transition>>,
+}
+
+/// Evaluates the schedule at `at` (default now) and lists the next `count` status changes after it.
+async fn status(Query(query): Query<StatusQuery>) -> Json<StatusResponse> {
+    let (_file_path, _file, data) = load_json::<ClosedData>(None,"closed.json".to_string(),true).await.unwrap();
+    _file.unlock().unwrap();
+
+    let clock = FixedClock(query.at.unwrap_or_else(|| SystemClock.now()));
+    let count = query.count.unwrap_or(5).min(100);
+
+    let tz = load_timezone().await.unwrap();
+    let cause = data.mode_cause(&clock);
+    return Json(StatusResponse {
+        at: clock.0,
+        at_local: format_local(clock.0, &tz),
+        timezone: tz.name().to_string(),
+        closed: data.is_currently_closed(&clock),
+        cause,
+        label: data.cause_label(&cause).map(|x| x.to_string()),
+        transitions: data.transitions(&clock, count).into_iter().map(|x| x.localized(&tz)).collect(),
+    });
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Shorthand for `from` and `to` both set to this.
+    at: Option<i64>,
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    from: i64,
+    to: i64,
+    timezone: String,
+    /// The change in effect at `from`, none if the history starts later.
+    in_effect: Option<Localized<HistoryEntry>>,
+    /// Changes after `from` up to `to`, oldest first, keeping the newest `limit`.
+    changes: Vec<Localized<HistoryEntry>>,
+}
+
+/// Recorded status changes, by default over the last week, so past statuses can be looked up.
+async fn history(Query(query): Query<HistoryQuery>) -> Json<HistoryResponse> {
+    let (_file_path, file, log) = load_json::<HistoryLog>(None,"history.json".to_string(),true).await.unwrap();
+    file.unlock().unwrap();
+
+    let now = SystemClock.now();
+    let to = query.at.or(query.to).unwrap_or(now);
+    let from = query.at.or(query.from).unwrap_or(to.saturating_sub(7 * 86400));
+    let limit = query.limit.unwrap_or(100).min(1000);
+
+    let tz = load_timezone().await.unwrap();
+    let localize = |x: &HistoryEntry| Localized{at_local: format_local(x.at, &tz), value: x.clone()};
+
+    let changes = log.between(from.saturating_add(1), to);
+    return Json(HistoryResponse {
+        from,
+        to,
+        timezone: tz.name().to_string(),
+        in_effect: log.status_at(from).map(localize),
+        changes: changes[changes.len().saturating_sub(limit)..].iter().map(localize).collect(),
+    });
+}
+
+/// Server-sent events stream with a `transition` event each time the closed status changes.
+async fn events(State(scheduler): State<Scheduler>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    //Transitions missed by a slow client are dropped rather than ending the stream.
+    let stream = BroadcastStream::new(scheduler.subscribe())
+        .filter_map(|x| x.ok())
+        .then(|x| async move {
+            let tz = load_timezone().await.unwrap();
+            Ok(Event::default().event("transition").json_data(x.localized(&tz)).unwrap())
+        });
+
+    return Sse::new(stream).keep_alive(KeepAlive::default());
+}
+
+/// Prometheus text format metrics for the scheduler.
+async fn metrics(State(scheduler): State<Scheduler>) -> Response {
+    let metrics = &scheduler.metrics;
+    let body = format!(
+        "# HELP headless_closed Whether the headless is currently closed.\n\
+        # TYPE headless_closed gauge\n\
+        headless_closed {}\n\
+        # HELP headless_transitions_total Status changes made by the scheduler since startup.\n\
+        # TYPE headless_transitions_total counter\n\
+        headless_transitions_total{{to=\"closed\"}} {}\n\
+        headless_transitions_total{{to=\"open\"}} {}\n\
+        # HELP headless_last_transition_timestamp_seconds When the status last changed, 0 if it hasn't since startup.\n\
+        # TYPE headless_last_transition_timestamp_seconds gauge\n\
+        headless_last_transition_timestamp_seconds {}\n\
+        # HELP headless_next_event_timestamp_seconds When the next scheduled event fires, 0 if there is none.\n\
+        # TYPE headless_next_event_timestamp_seconds gauge\n\
+        headless_next_event_timestamp_seconds {}\n\
+        # HELP headless_webhook_failures_total Transition webhooks that failed to send.\n\
+        # TYPE headless_webhook_failures_total counter\n\
+        headless_webhook_failures_total {}\n",
+        metrics.closed.load(Ordering::Relaxed) as u8,
+        metrics.transitions_to_closed.load(Ordering::Relaxed),
+        metrics.transitions_to_open.load(Ordering::Relaxed),
+        metrics.last_transition.load(Ordering::Relaxed),
+        metrics.next_transition.load(Ordering::Relaxed),
+        metrics.webhook_failures.load(Ordering::Relaxed),
+    );
+
+    return ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response();
+}
+
+async fn open(headers: HeaderMap) -> Response {
+    return with_etag(&headers, open_list().await);
+}
+
+async fn open_list() -> String {
     let dir = get_dir().unwrap();
     //First read from the admin file as it's already in the format we need
     let file_path = dir.join("usersadmin.txt");
@@ -76,6 +284,13 @@ async fn open() -> String {
     buf.read_to_string(&mut data_string).await.unwrap();
 
     file.unlock().unwrap();
+
+    let expiries = load_expiries().await.unwrap();
+    let now = SystemClock.now();
+    let mut data_string = data_string.lines()
+        .filter(|x| expiries.is_active(WhitelistName::Admin, x, now))
+        .collect::<Vec<&str>>()
+        .join("\n");
     
     //Next read from the auth file and do it by line so we only grab the bits we want.
     let dir = get_dir().unwrap();
@@ -115,6 +330,10 @@ async fn open_is_whitelisted(Path(userid): Path<String>) -> String {
     }
 
     file.unlock().unwrap();
+
+    let expiries = load_expiries().await.unwrap();
+    let now = SystemClock.now();
+    lines.retain(|x| expiries.is_active(WhitelistName::Admin, x, now));
     
     //Next read from the auth file and do it by line so we only grab the bits we want.
     let dir = get_dir().unwrap();
@@ -139,7 +358,11 @@ async fn open_is_whitelisted(Path(userid): Path<String>) -> String {
     return "FALSE".to_string();
 }
 
-async fn closed() -> String {
+async fn closed(headers: HeaderMap) -> Response {
+    return with_etag(&headers, closed_list().await);
+}
+
+async fn closed_list() -> String {
     let dir = get_dir().unwrap();
     //Closed whitelist is all in one file so we just read the file basically.
     let file_path = dir.join("usersclosed.txt");
@@ -153,7 +376,11 @@ async fn closed() -> String {
     buf.read_to_string(&mut data_string).await.unwrap();
 
     file.unlock().unwrap();
-    return data_string.trim().to_string();
+
+    let expiries = load_expiries().await.unwrap();
+    let now = SystemClock.now();
+    let active: Vec<&str> = data_string.lines().filter(|x| expiries.is_active(WhitelistName::Closed, x, now)).collect();
+    return active.join("\n").trim().to_string();
 }
 
 async fn closed_is_whitelisted(Path(userid): Path<String>) -> String {
@@ -175,8 +402,124 @@ async fn closed_is_whitelisted(Path(userid): Path<String>) -> String {
 
     file.unlock().unwrap();
 
-    if lines.contains(&userid) {
+    let expiries = load_expiries().await.unwrap();
+    if lines.contains(&userid) && expiries.is_active(WhitelistName::Closed, &userid, SystemClock.now()) {
         return "TRUE".to_string()
     }
     return "FALSE".to_string();
+}
+
+async fn read_lines(file_name: &str) -> Vec<String> {
+    let dir = get_dir().unwrap();
+    let file_path = dir.join(file_name);
+
+    let mut file = try_get_file(None, &file_path).await.unwrap();
+    file.lock_shared().unwrap();
+
+    let buf = BufReader::new(&mut file);
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut lines_reader = buf.lines();
+
+    while let Some(next_line) = lines_reader.next_line().await.unwrap() {
+        lines.push(next_line);
+    }
+
+    file.unlock().unwrap();
+    return lines;
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    since: u64,
+}
+
+#[derive(Serialize, Default)]
+struct ListDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ChangesResponse {
+    revision: u64,
+    open: ListDiff,
+    closed: ListDiff,
+    mode: Option<ClosedStatus>,
+}
+
+async fn changes(Query(query): Query<ChangesQuery>) -> Response {
+    //Expired entries that haven't been purged yet are already left out of the full lists.
+    let expiries = load_expiries().await.unwrap();
+    let now = SystemClock.now();
+
+    //Hold the log while reading the lists, writers bump it before releasing, so the lists match its revision.
+    let (_file_path, file, log) = load_json::<RevisionLog>(None,"revisions.json".to_string(),true).await.unwrap();
+
+    if !log.covers(query.since) {
+        file.unlock().unwrap();
+        return (StatusCode::GONE, "Revision is no longer available, fetch the full lists instead").into_response();
+    }
+
+    //Net effect of the changes on each list entry as (present before, present after).
+    let mut net: HashMap<(WhitelistName, String), (bool, bool)> = HashMap::new();
+    let mut mode: Option<ClosedStatus> = None;
+    for change in log.changes_since(query.since) {
+        let (key, present) = match change {
+            Change::Added{list, uid} => ((*list, uid.clone()), true),
+            Change::Removed{list, uid} => ((*list, uid.clone()), false),
+            Change::Mode{status, ..} => {
+                mode = Some(*status);
+                continue;
+            },
+        };
+        net.entry(key).and_modify(|x| x.1 = present).or_insert((!present, present));
+    }
+
+    let admin: HashSet<String> = read_lines("usersadmin.txt").await.into_iter()
+        .filter(|x| expiries.is_active(WhitelistName::Admin, x, now))
+        .collect();
+    let auth: HashSet<String> = read_lines("usersauth.txt").await.iter()
+        .filter_map(|x| x.split('=').nth(1))
+        .map(|x| x.to_string())
+        .collect();
+    let closed: HashSet<String> = read_lines("usersclosed.txt").await.into_iter()
+        .filter(|x| expiries.is_active(WhitelistName::Closed, x, now))
+        .collect();
+    file.unlock().unwrap();
+
+    let present_before = |list: WhitelistName, uid: &String, now: &HashSet<String>| -> bool {
+        match net.get(&(list, uid.clone())) {
+            Some((before, _after)) => *before,
+            None => now.contains(uid),
+        }
+    };
+
+    let mut open_diff = ListDiff::default();
+    let mut closed_diff = ListDiff::default();
+    let mut seen: HashSet<&String> = HashSet::new();
+    for (list, uid) in net.keys() {
+        if *list == WhitelistName::Closed {
+            let (before, after) = (present_before(*list, uid, &closed), closed.contains(uid));
+            if before && !after {
+                closed_diff.removed.push(uid.clone());
+            } else if after && !before {
+                closed_diff.added.push(uid.clone());
+            }
+            continue;
+        }
+
+        if !seen.insert(uid) {
+            continue;
+        }
+        let before = present_before(WhitelistName::Admin, uid, &admin) || present_before(WhitelistName::Auth, uid, &auth);
+        let after = admin.contains(uid) || auth.contains(uid);
+        if before && !after {
+            open_diff.removed.push(uid.clone());
+        } else if after && !before {
+            open_diff.added.push(uid.clone());
+        }
+    }
+
+    return Json(ChangesResponse{revision: log.revision, open: open_diff, closed: closed_diff, mode}).into_response();
 }
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+//Past this many tracked clients we drop any bucket that has refilled completely, since it holds no state worth keeping.
+const PRUNE_THRESHOLD: usize = 10000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+//Used for anonymous clients unless configured, and for any limit that is configured wrong.
+const DEFAULT_LIMIT: Limit = Limit{burst: 20.0, per_second: 5.0};
+
+impl Limit {
+    /// Reads `{prefix}_BURST` and `{prefix}_RATE` from the environment, falling back to `default` for whichever is unset.
+    /// Without a default there is no limit if neither is set, and otherwise the rate is required and the burst defaults to one second's worth of requests.
+    /// Values that aren't numbers or would never allow a request are logged and replaced with the default,
+    /// or `DEFAULT_LIMIT` without one, rather than turning limiting off.
+    fn from_env(prefix: &str, default: Option<Limit>) -> Option<Limit> {
+        let fallback = default.unwrap_or(DEFAULT_LIMIT);
+        let read = |name: String| -> Result<Option<f64>, String> {
+            match std::env::var(&name) {
+                Ok(value) => value.trim().parse::<f64>().ok().filter(|x| x.is_finite()).map(Some).ok_or(format!("{name}=`{value}` is not a number")),
+                Err(_) => Ok(None),
+            }
+        };
+        let (burst, per_second) = match (read(format!("{prefix}_BURST")), read(format!("{prefix}_RATE"))) {
+            (Ok(burst), Ok(per_second)) => (burst, per_second),
+            (Err(e), _) | (_, Err(e)) => {
+                println!("rate limit:{e}, using {fallback:?}");
+                return Some(fallback);
+            },
+        };
+
+        let per_second = match per_second.or(default.map(|x| x.per_second)) {
+            Some(per_second) => per_second,
+            None if burst.is_none() => return None,
+            None => {
+                println!("rate limit:{prefix}_BURST is set without {prefix}_RATE, using {fallback:?}");
+                return Some(fallback);
+            },
+        };
+        let burst = burst.or(default.map(|x| x.burst)).unwrap_or(per_second);
+        let limit = Limit{burst, per_second};
+
+        if limit.per_second <= 0.0 || limit.burst < 1.0 {
+            println!("rate limit:{prefix} {limit:?} would never allow a request, using {fallback:?}");
+            return Some(fallback);
+        }
+        Some(limit)
+    }
+}
+
+/// Compares in time that only depends on the lengths, not on where the first difference is.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.last = now;
+    }
+
+    /// Takes a token if one is available, otherwise returns the number of whole seconds until one will be.
+    fn take(&mut self, limit: Limit, now: Instant) -> Result<(), u64> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(((1.0 - self.tokens) / limit.per_second).ceil() as u64)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    Key(String),
+}
+
+/// Token bucket rate limiting for the web endpoints, tracked per IP address and per API key.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    ip_limit: Option<Limit>,
+    key_limit: Option<Limit>,
+    api_keys: Arc<Vec<String>>,
+    buckets: Arc<Mutex<HashMap<Client, Bucket>>>,
+}
+
+impl RateLimiter {
+    /// Configures the limiter from the environment.
+    ///
+    /// `RATE_LIMIT_IP_BURST`/`RATE_LIMIT_IP_RATE` apply to anonymous clients and default to a burst of 20 at 5 requests per second.
+    /// Clients sending one of the comma separated `HEADLESS_API_KEYS` in the `X-Api-Key` header are limited by
+    /// `RATE_LIMIT_KEY_BURST`/`RATE_LIMIT_KEY_RATE` instead, and are exempt if both are unset.
+    pub fn from_env() -> Self {
+        let api_keys = std::env::var("HEADLESS_API_KEYS").unwrap_or_default()
+            .split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<String>>();
+
+        RateLimiter {
+            ip_limit: Limit::from_env("RATE_LIMIT_IP", Some(DEFAULT_LIMIT)),
+            key_limit: Limit::from_env("RATE_LIMIT_KEY", None),
+            api_keys: Arc::new(api_keys),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn api_key(&self, headers: &HeaderMap) -> Option<String> {
+        let key = headers.get("x-api-key")?.to_str().ok()?;
+        //Checks every key without stopping early, so response times don't hint at how much of a key was right.
+        if self.api_keys.iter().fold(false, |found, x| found | constant_time_eq(x.as_bytes(), key.as_bytes())) {
+            return Some(key.to_string());
+        }
+        None
+    }
+
+    fn check(&self, client: Client, limit: Limit) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|client, bucket| {
+                let limit = match client {
+                    Client::Ip(_) => self.ip_limit,
+                    Client::Key(_) => self.key_limit,
+                };
+                match limit {
+                    Some(limit) => {
+                        bucket.refill(limit, now);
+                        bucket.tokens < limit.burst
+                    },
+                    None => false,
+                }
+            });
+        }
+
+        buckets.entry(client)
+            .or_insert(Bucket{tokens: limit.burst, last: now})
+            .take(limit, now)
+    }
+}
+
+/// Middleware rejecting clients that have used up their bucket with a 429 and a `Retry-After` header.
+pub async fn limit<B>(
+    State(limiter): State<RateLimiter>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let (client, limit) = match limiter.api_key(request.headers()) {
+        Some(key) => (Client::Key(key), limiter.key_limit),
+        None => (Client::Ip(addr.ip()), limiter.ip_limit),
+    };
+
+    if let Some(limit) = limit {
+        if let Err(retry_after) = limiter.check(client, limit) {
+            let mut response = (StatusCode::TOO_MANY_REQUESTS, "Too many requests").into_response();
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after.max(1)));
+            return response;
+        }
+    }
+
+    next.run(request).await
+}
@@ -0,0 +1,158 @@
+use fs4::tokio::AsyncFileExt;
+
+use tokio::fs::File;
+use tokio::io::{BufReader, AsyncBufReadExt};
+
+use serde::Serialize;
+
+use crate::clock::{Clock, FixedClock, SystemClock};
+use crate::commonio::*;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    AdminWhitelist,
+    Registered,
+    ClosedWhitelist,
+    NotInOpenWhitelist,
+    NotInClosedWhitelist,
+}
+
+impl Reason {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Reason::AdminWhitelist => "in the admin whitelist",
+            Reason::Registered => "registered through Discord",
+            Reason::ClosedWhitelist => "in the closed whitelist",
+            Reason::NotInOpenWhitelist => "neither in the admin whitelist nor registered",
+            Reason::NotInClosedWhitelist => "not in the closed whitelist while the headless is closed",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Decision {
+    pub whitelisted: bool,
+    pub reason: Reason,
+}
+
+/// Everything that went into a whitelist decision for a single UserID.
+#[derive(Serialize, Debug)]
+pub struct Explanation {
+    pub userid: String,
+    pub closed: bool,
+    pub cause: ModeCause,
+    pub in_admin_whitelist: bool,
+    pub registered_by: Vec<String>,
+    pub in_closed_whitelist: bool,
+    pub decision: Decision,
+}
+
+/// A consistent view of the closed status and all whitelists, read while holding shared locks on every file.
+#[derive(Debug)]
+pub struct WhitelistSnapshot {
+    pub closed_data: ClosedData,
+    pub admin: Vec<String>,
+    /// Pairs of Discord user ID and Resonite UserID from `usersauth.txt`.
+    pub auth: Vec<(String, String)>,
+    pub closed: Vec<String>,
+    /// When the snapshot was taken, which every decision from it is made as of.
+    pub now: i64,
+    /// Whether the headless was closed at `now`.
+    pub currently_closed: bool,
+}
+
+async fn read_lines(file: &mut File) -> Result<Vec<String>, Error> {
+    let buf = BufReader::new(file);
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut lines_reader = buf.lines();
+
+    while let Some(next_line) = lines_reader.next_line().await? {
+        lines.push(next_line);
+    }
+    Ok(lines)
+}
+
+impl WhitelistSnapshot {
+    pub async fn load() -> Result<Self, Error> {
+        //Read before locking any list, since adding a temporary entry locks the expiries first.
+        let expiries = load_expiries().await?;
+        let (_file_path, closed_file, closed_data) = load_json::<ClosedData>(None, "closed.json".to_string(), true).await?;
+
+        let dir = get_dir()?;
+        let mut admin_file = try_get_file(None, &dir.join("usersadmin.txt")).await?;
+        admin_file.lock_shared()?;
+        let mut auth_file = try_get_file(None, &dir.join("usersauth.txt")).await?;
+        auth_file.lock_shared()?;
+        let mut closed_list_file = try_get_file(None, &dir.join("usersclosed.txt")).await?;
+        closed_list_file.lock_shared()?;
+
+        let now = SystemClock.now();
+
+        //Temporary entries that have run out but haven't been purged from the files yet don't count.
+        let admin = read_lines(&mut admin_file).await?.into_iter()
+            .filter(|x| expiries.is_active(WhitelistName::Admin, x, now))
+            .collect();
+        let auth = read_lines(&mut auth_file).await?.into_iter()
+            .filter_map(|x| {
+                let (discord_id, uid) = x.split_once('=')?;
+                Some((discord_id.to_string(), uid.to_string()))
+            })
+            .collect();
+        let closed = read_lines(&mut closed_list_file).await?.into_iter()
+            .filter(|x| expiries.is_active(WhitelistName::Closed, x, now))
+            .collect();
+
+        closed_list_file.unlock()?;
+        auth_file.unlock()?;
+        admin_file.unlock()?;
+        closed_file.unlock()?;
+
+        let currently_closed = closed_data.is_currently_closed(&FixedClock(now));
+        Ok(WhitelistSnapshot{closed_data, admin, auth, closed, now, currently_closed})
+    }
+
+    pub fn in_admin(&self, uid: &str) -> bool {
+        self.admin.iter().any(|x| x == uid)
+    }
+
+    /// Discord user IDs that have registered the given UserID.
+    pub fn registered_by(&self, uid: &str) -> Vec<&str> {
+        self.auth.iter().filter(|(_, x)| x == uid).map(|(discord_id, _)| discord_id.as_str()).collect()
+    }
+
+    pub fn in_closed(&self, uid: &str) -> bool {
+        self.closed.iter().any(|x| x == uid)
+    }
+
+    /// Decides whether a UserID may join, based on whether the headless is currently closed.
+    pub fn check(&self, uid: &str) -> Decision {
+        if self.currently_closed {
+            if self.in_closed(uid) {
+                return Decision{whitelisted: true, reason: Reason::ClosedWhitelist};
+            }
+            return Decision{whitelisted: false, reason: Reason::NotInClosedWhitelist};
+        }
+
+        if self.in_admin(uid) {
+            return Decision{whitelisted: true, reason: Reason::AdminWhitelist};
+        }
+        if !self.registered_by(uid).is_empty() {
+            return Decision{whitelisted: true, reason: Reason::Registered};
+        }
+        Decision{whitelisted: false, reason: Reason::NotInOpenWhitelist}
+    }
+
+    pub fn explain(&self, uid: &str) -> Explanation {
+        Explanation {
+            userid: uid.to_string(),
+            closed: self.currently_closed,
+            cause: self.closed_data.mode_cause(&FixedClock(self.now)),
+            in_admin_whitelist: self.in_admin(uid),
+            registered_by: self.registered_by(uid).into_iter().map(|x| x.to_string()).collect(),
+            in_closed_whitelist: self.in_closed(uid),
+            decision: self.check(uid),
+        }
+    }
+}